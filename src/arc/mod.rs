@@ -1,14 +1,64 @@
 use std::{
+    alloc::{self, AllocError, Allocator, Global, Layout},
+    any::Any,
+    borrow::Borrow,
     cell::UnsafeCell,
+    cmp::Ordering as CmpOrdering,
+    fmt,
+    hash::{Hash, Hasher},
     hint::spin_loop,
-    mem::ManuallyDrop,
-    ops::Deref,
-    ptr::NonNull,
+    marker::Unsize,
+    mem::{self, ManuallyDrop, MaybeUninit},
+    ops::{CoerceUnsized, Deref},
+    process,
+    ptr::{self, NonNull},
     sync::atomic::{fence, AtomicUsize, Ordering},
     thread,
 };
 
-struct ArcData<T> {
+// Counts above this abort the process. Far below `usize::MAX`, which `get_mut`
+// uses as a lock, even if every thread increments at once before aborting.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+// A `mem::forget` loop could overflow a count, and free the data while it's in use.
+// Shared with `rc::Rc`.
+#[cold]
+pub(crate) fn refcount_overflow() -> ! {
+    eprintln!("reference count overflow");
+    process::abort()
+}
+
+// Moves a boxed, possibly unsized value into a new allocation laid out like a repr(C)
+// struct of `counts` followed by the value, which is how `ArcData` and `rc::RcData`
+// start. The pointer carries the box's metadata, callers cast it to their own data type.
+pub(crate) fn move_into_counted<C, T: ?Sized>(counts: C, v: Box<T>) -> NonNull<T> {
+    let value_layout = Layout::for_value(&*v);
+    let (layout, offset) = Layout::new::<C>().extend(value_layout).unwrap();
+    // the same layout the data type has, which `Weak::drop` frees it with
+    let layout = layout.pad_to_align();
+
+    unsafe {
+        let mem = alloc::alloc(layout);
+        if mem.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        let v = Box::into_raw(v);
+        let data = mem.with_metadata_of(v);
+        (mem as *mut C).write(counts);
+        ptr::copy_nonoverlapping(v as *const u8, mem.add(offset), value_layout.size());
+
+        // free the box, the value was moved
+        drop(Box::from_raw(v as *mut ManuallyDrop<T>));
+        NonNull::new_unchecked(data)
+    }
+}
+
+// repr(C), so that `new_cyclic` can allocate an `ArcData<MaybeUninit<T>>` and
+// use it as an `ArcData<T>` once the value is written, and an unsized value
+// always starts right after the counts, padded to its alignment.
+#[repr(C)]
+struct ArcData<T: ?Sized> {
     // Number of Arc's
     data_ref_count: AtomicUsize,
     // Number of `Weak`s, plus one if there are any `Arc`s.
@@ -17,31 +67,202 @@ struct ArcData<T> {
     data: UnsafeCell<ManuallyDrop<T>>,
 }
 
-pub struct Arc<T> {
+pub struct Arc<T: ?Sized, A: Allocator = Global> {
     ptr: NonNull<ArcData<T>>,
+    // every Arc and Weak has a copy, the last one to go frees the memory with it
+    alloc: A,
 }
 
-unsafe impl<T> Sync for Arc<T> where T: Sync + Send {}
-unsafe impl<T> Send for Arc<T> where T: Send + Sync {}
+unsafe impl<T: ?Sized, A: Allocator + Sync> Sync for Arc<T, A> where T: Sync + Send {}
+unsafe impl<T: ?Sized, A: Allocator + Send> Send for Arc<T, A> where T: Send + Sync {}
+
+// `Arc<T>` to `Arc<dyn Trait>` or `Arc<[T; N]>` to `Arc<[T]>`, like a reference
+impl<T: ?Sized + Unsize<U>, U: ?Sized, A: Allocator> CoerceUnsized<Arc<U, A>> for Arc<T, A> {}
 
 impl<T> Arc<T> {
     #[allow(dead_code)]
     pub fn new(v: T) -> Self {
-        Self {
-            ptr: NonNull::from(Box::leak(Box::new(ArcData {
+        Self::new_in(v, Global)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_new(v: T) -> Result<Self, AllocError> {
+        Self::try_new_in(v, Global)
+    }
+
+    /// Creates an `Arc` of a value that holds a `Weak` to itself, `f` gets that
+    /// `Weak` before the value exists, upgrading it fails until `new_cyclic` returns.
+    #[allow(dead_code)]
+    pub fn new_cyclic(f: impl FnOnce(&Weak<T>) -> T) -> Self {
+        // no Arc yet, just the weak pointer handed to `f`
+        let uninit = Box::leak(Box::new(ArcData {
+            data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::<T>::uninit())),
+            data_ref_count: AtomicUsize::new(0),
+            alloc_ref_count: AtomicUsize::new(1),
+        }));
+        let weak = Weak {
+            ptr: NonNull::from(uninit).cast::<ArcData<T>>(),
+            alloc: Global,
+        };
+
+        // if `f` panics, dropping `weak` frees the allocation without touching the value
+        let v = f(&weak);
+
+        unsafe { (*weak.ptr.as_ref().data.get()) = ManuallyDrop::new(v) };
+        // Release pairs with the Acquire in `upgrade`, upgrading sees the value.
+        weak.data().data_ref_count.store(1, Ordering::Release);
+
+        // the weak count we started with becomes the one all Arcs share
+        let weak = ManuallyDrop::new(weak);
+        Arc {
+            ptr: weak.ptr,
+            alloc: Global,
+        }
+    }
+}
+
+impl<T, A: Allocator> Arc<T, A> {
+    #[allow(dead_code)]
+    pub fn new_in(v: T, alloc: A) -> Self {
+        match Self::try_new_in(v, alloc) {
+            Ok(arc) => arc,
+            Err(AllocError) => alloc::handle_alloc_error(Layout::new::<ArcData<T>>()),
+        }
+    }
+
+    /// Like `new_in`, but fails instead of aborting when the allocator is out of memory.
+    #[allow(dead_code)]
+    pub fn try_new_in(v: T, alloc: A) -> Result<Self, AllocError> {
+        let data = Box::try_new_in(
+            ArcData {
                 data: UnsafeCell::new(ManuallyDrop::new(v)),
                 data_ref_count: AtomicUsize::new(1),
                 alloc_ref_count: AtomicUsize::new(1),
-            }))),
+            },
+            alloc,
+        )?;
+        let (ptr, alloc) = Box::into_raw_with_allocator(data);
+        Ok(Arc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            alloc,
+        })
+    }
+
+    /// Returns the value if this is the only `Arc`, otherwise gives the `Arc` back.
+    #[allow(dead_code)]
+    pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(arc);
+        }
+        fence(Ordering::Acquire);
+
+        Ok(unsafe { Self::take_and_release(arc) })
+    }
+
+    /// Returns the value if this is the last `Arc`. Unlike `try_unwrap`, when
+    /// two threads race to drop the last two `Arc`s, exactly one gets the value.
+    #[allow(dead_code)]
+    pub fn into_inner(arc: Self) -> Option<T> {
+        let arc = ManuallyDrop::new(arc);
+        if arc.data().data_ref_count.fetch_sub(1, Ordering::Release) != 1 {
+            return None;
         }
+        fence(Ordering::Acquire);
+
+        Some(unsafe { Self::take_and_release(ManuallyDrop::into_inner(arc)) })
     }
 
+    // Safety: the data count has just dropped to zero through `arc`, so nobody
+    // else can reach the value.
+    unsafe fn take_and_release(arc: Self) -> T {
+        let arc = ManuallyDrop::new(arc);
+        let v = ManuallyDrop::take(&mut *arc.data().data.get());
+        // the weak count all Arcs shared
+        drop(Weak {
+            ptr: arc.ptr,
+            alloc: ptr::read(&arc.alloc),
+        });
+        v
+    }
+
+    /// Gives mutable access to the value, cloning it first unless this `Arc` is
+    /// the only one. Remaining `Weak`s are left behind with the old allocation.
+    #[allow(dead_code)]
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+        A: Clone,
+    {
+        // taking the data count to zero stops `Weak`s from upgrading meanwhile
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // other Arcs share the value
+            *arc = Arc::new_in((**arc).clone(), arc.alloc.clone());
+        } else if arc.data().alloc_ref_count.load(Ordering::Relaxed) != 1 {
+            // the only Arc, but there are `Weak`s. Move the value out, they
+            // can't upgrade to it anymore.
+            let v = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+            let fresh = Arc::new_in(v, arc.alloc.clone());
+            let old = ManuallyDrop::new(mem::replace(arc, fresh));
+            drop(Weak {
+                ptr: old.ptr,
+                alloc: unsafe { ptr::read(&old.alloc) },
+            });
+        } else {
+            // unique after all, undo
+            arc.data().data_ref_count.store(1, Ordering::Release);
+        }
+
+        unsafe { &mut *arc.data().data.get() }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Arc<T, A> {
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
 
     #[allow(dead_code)]
-    pub fn downgrade(arc: &Self) -> Weak<T> {
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.data().data_ref_count.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn weak_count(arc: &Self) -> usize {
+        match arc.data().alloc_ref_count.load(Ordering::Relaxed) {
+            // locked by `get_mut`, which only happens when there are no `Weak`s
+            usize::MAX => 0,
+            // don't count the one shared by all Arcs
+            n => n - 1,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        // only the address, the vtables of the same type may differ
+        ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    #[allow(dead_code)]
+    pub fn as_ptr(arc: &Self) -> *const T {
+        // no reference to the data, another thread may be allowed to write through `get_mut`
+        unsafe { UnsafeCell::raw_get(ptr::addr_of!((*arc.ptr.as_ptr()).data)) as *const T }
+    }
+
+    #[allow(dead_code)]
+    pub fn downgrade(arc: &Self) -> Weak<T, A>
+    where
+        A: Clone,
+    {
         let mut n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
 
         loop {
@@ -50,6 +271,9 @@ impl<T> Arc<T> {
                 n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
                 continue;
             }
+            if n > MAX_REFCOUNT {
+                refcount_overflow();
+            }
 
             if let Err(e) = arc.data().alloc_ref_count.compare_exchange_weak(
                 n,
@@ -61,7 +285,10 @@ impl<T> Arc<T> {
                 continue;
             }
 
-            return Weak { ptr: arc.ptr };
+            return Weak {
+                ptr: arc.ptr,
+                alloc: arc.alloc.clone(),
+            };
         }
     }
 
@@ -98,7 +325,33 @@ impl<T> Arc<T> {
     }
 }
 
-impl<T> Deref for Arc<T> {
+// `from_raw` can't know the allocator
+impl<T: ?Sized> Arc<T> {
+    /// Gives up the `Arc` without decrementing the count, use `from_raw` to
+    /// get it back.
+    #[allow(dead_code)]
+    pub fn into_raw(arc: Self) -> *const T {
+        let ptr = Self::as_ptr(&arc);
+        mem::forget(arc);
+        ptr
+    }
+
+    /// # Safety
+    /// `ptr` has to come from `into_raw`, and every call takes back one `Arc`.
+    #[allow(dead_code)]
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // the counts, padded to the alignment of the value, see `ArcData`
+        let (_, offset) = Layout::new::<ArcData<()>>()
+            .extend(Layout::for_value(&*ptr))
+            .unwrap();
+        Arc {
+            ptr: NonNull::new_unchecked(ptr.byte_sub(offset) as *mut ArcData<T>),
+            alloc: Global,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Deref for Arc<T, A> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -106,17 +359,22 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized, A: Allocator + Clone> Clone for Arc<T, A> {
     fn clone(&self) -> Self {
-        // TODO: hanlde overflows
-        self.data().data_ref_count.fetch_add(1, Ordering::Relaxed);
-        Arc { ptr: self.ptr }
+        if self.data().data_ref_count.fetch_add(1, Ordering::Relaxed) > MAX_REFCOUNT {
+            refcount_overflow();
+        }
+        Arc {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+        }
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized, A: Allocator> Drop for Arc<T, A> {
     fn drop(&mut self) {
-        if self.data().data_ref_count.fetch_sub(1, Ordering::Relaxed) == 1 {
+        // Release so that the last one, after its Acquire fence, sees all uses of the value
+        if self.data().data_ref_count.fetch_sub(1, Ordering::Release) == 1 {
             fence(Ordering::Acquire);
             unsafe {
                 // 这里一定安全嘛？可能有其它的 Weak 此时同时 upgrade? 为了避免这个问题，需要在 Weak upgrade 的时候使用 compare_and_change
@@ -125,66 +383,253 @@ impl<T> Drop for Arc<T> {
                 ManuallyDrop::drop(self.ptr.as_mut().data.get_mut());
 
                 // 因为所有的 Arc 对应一个 Weak，当没有 Arc 的时候就 Drop 掉对应的 Weak
-                drop(Weak { ptr: self.ptr });
+                // the allocator stays with `self`, the Weak borrows it
+                drop(Weak {
+                    ptr: self.ptr,
+                    alloc: &self.alloc,
+                });
             }
         }
     }
 }
 
-pub struct Weak<T> {
+pub struct Weak<T: ?Sized, A: Allocator = Global> {
     ptr: NonNull<ArcData<T>>,
+    alloc: A,
 }
 
-unsafe impl<T: Sync + Send> Sync for Weak<T> {}
-unsafe impl<T: Sync + Send> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send, A: Allocator + Sync> Sync for Weak<T, A> {}
+unsafe impl<T: ?Sized + Sync + Send, A: Allocator + Send> Send for Weak<T, A> {}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized, A: Allocator> CoerceUnsized<Weak<U, A>> for Weak<T, A> {}
 
 impl<T> Weak<T> {
+    /// A `Weak` that never upgrades, it doesn't allocate.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Weak {
+            ptr: NonNull::new(ptr::without_provenance_mut(usize::MAX)).unwrap(),
+            alloc: Global,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Weak<T, A> {
+    // `Weak::new` points at an address no allocation can have
+    fn is_dangling(&self) -> bool {
+        self.ptr.as_ptr() as *mut () as usize == usize::MAX
+    }
+
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
 
     #[allow(dead_code)]
-    pub fn upgrade(&self) -> Option<Arc<T>> {
+    pub fn upgrade(&self) -> Option<Arc<T, A>>
+    where
+        A: Clone,
+    {
+        if self.is_dangling() {
+            return None;
+        }
+
         let mut n = self.data().data_ref_count.load(Ordering::Relaxed);
 
         loop {
             if n == 0 {
                 return None;
             }
+            if n > MAX_REFCOUNT {
+                refcount_overflow();
+            }
 
+            // Acquire pairs with the Release in `new_cyclic`
             if let Err(e) = self.data().data_ref_count.compare_exchange_weak(
                 n,
                 n + 1,
-                Ordering::Relaxed,
+                Ordering::Acquire,
                 Ordering::Relaxed,
             ) {
                 n = e;
                 continue;
             }
 
-            return Some(Arc { ptr: self.ptr });
+            return Some(Arc {
+                ptr: self.ptr,
+                alloc: self.alloc.clone(),
+            });
         }
     }
 }
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized, A: Allocator + Clone> Clone for Weak<T, A> {
     fn clone(&self) -> Self {
-        self.data().alloc_ref_count.fetch_add(1, Ordering::Relaxed);
-        Weak { ptr: self.ptr }
+        if !self.is_dangling()
+            && self.data().alloc_ref_count.fetch_add(1, Ordering::Relaxed) > MAX_REFCOUNT
+        {
+            refcount_overflow();
+        }
+        Weak {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+        }
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized, A: Allocator> Drop for Weak<T, A> {
     fn drop(&mut self) {
-        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Relaxed) == 1 {
+        if self.is_dangling() {
+            return;
+        }
+
+        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
             fence(Ordering::Acquire);
             unsafe {
-                drop(Box::from_raw(self.ptr.as_ptr()));
+                drop(Box::from_raw_in(self.ptr.as_ptr(), &self.alloc));
             }
         }
     }
 }
 
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Debug for Weak<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(Weak)")
+    }
+}
+
+impl<A: Allocator> Arc<dyn Any + Send + Sync, A> {
+    /// Gets the `Arc` of the concrete type back, or itself if that's not the type.
+    #[allow(dead_code)]
+    pub fn downcast<T: Any + Send + Sync>(self) -> Result<Arc<T, A>, Self> {
+        if !(*self).is::<T>() {
+            return Err(self);
+        }
+
+        let arc = ManuallyDrop::new(self);
+        Ok(Arc {
+            ptr: arc.ptr.cast::<ArcData<T>>(),
+            alloc: unsafe { ptr::read(&arc.alloc) },
+        })
+    }
+}
+
+// Unsized values can't be passed around, they're moved out of a box instead.
+impl<T: ?Sized> From<Box<T>> for Arc<T> {
+    fn from(v: Box<T>) -> Self {
+        // data_ref_count and alloc_ref_count
+        let counts = [AtomicUsize::new(1), AtomicUsize::new(1)];
+        let data = move_into_counted(counts, v).as_ptr() as *mut ArcData<T>;
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(data) },
+            alloc: Global,
+        }
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(v: &str) -> Self {
+        Box::<str>::from(v).into()
+    }
+}
+
+impl From<String> for Arc<str> {
+    fn from(v: String) -> Self {
+        v.into_boxed_str().into()
+    }
+}
+
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    fn from(v: &[T]) -> Self {
+        Box::<[T]>::from(v).into()
+    }
+}
+
+impl<T> From<Vec<T>> for Arc<[T]> {
+    fn from(v: Vec<T>) -> Self {
+        v.into_boxed_slice().into()
+    }
+}
+
+impl<T> FromIterator<T> for Arc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<T>>().into()
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(v: T) -> Self {
+        Arc::new(v)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> AsRef<T> for Arc<T, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Borrow<T> for Arc<T, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, A: Allocator> fmt::Display for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Pointer for Arc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&Self::as_ptr(self), f)
+    }
+}
+
+impl<T: ?Sized + PartialEq, A: Allocator> PartialEq for Arc<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for Arc<T, A> {}
+
+impl<T: ?Sized + PartialOrd, A: Allocator> PartialOrd for Arc<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord, A: Allocator> Ord for Arc<T, A> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash, A: Allocator> Hash for Arc<T, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
 #[test]
 fn test_custom_arc() {
     static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
@@ -223,3 +668,348 @@ fn test_custom_arc() {
 
     assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
 }
+
+#[test]
+fn test_arc_api() {
+    use std::collections::HashSet;
+
+    let a = Arc::new(String::from("a"));
+    let b = a.clone();
+    let w = Arc::downgrade(&a);
+    assert_eq!(Arc::strong_count(&a), 2);
+    assert_eq!(Arc::weak_count(&a), 1);
+    assert!(Arc::ptr_eq(&a, &b));
+    assert!(!Arc::ptr_eq(&a, &Arc::new(String::from("a"))));
+
+    // two Arcs, no unwrapping
+    let a = Arc::try_unwrap(a).unwrap_err();
+    assert_eq!(Arc::into_inner(a), None);
+    assert_eq!(Arc::try_unwrap(b).unwrap(), "a");
+    assert!(w.upgrade().is_none());
+
+    // make_mut clones a shared value, and leaves weak pointers behind
+    let mut a = Arc::new(1);
+    let b = a.clone();
+    *Arc::make_mut(&mut a) += 1;
+    assert_eq!((*a, *b), (2, 1));
+    let w = Arc::downgrade(&a);
+    *Arc::make_mut(&mut a) += 1;
+    assert_eq!(*a, 3);
+    assert!(w.upgrade().is_none());
+    let p = Arc::as_ptr(&a);
+    *Arc::make_mut(&mut a) += 1;
+    assert_eq!(Arc::as_ptr(&a), p);
+
+    let raw = Arc::into_raw(a);
+    assert_eq!(unsafe { *raw }, 4);
+    let a = unsafe { Arc::from_raw(raw) };
+    assert_eq!(Arc::into_inner(a), Some(4));
+
+    let w = Weak::<i32>::new();
+    assert!(w.clone().upgrade().is_none());
+
+    let set: HashSet<Arc<i32>> = [Arc::from(1), Arc::default(), Arc::new(1)].into();
+    assert_eq!(set.len(), 2);
+    assert!(Arc::new(1) < Arc::new(2));
+    assert_eq!(format!("{:?} {}", Arc::new("a"), Arc::new(1)), "\"a\" 1");
+}
+
+#[test]
+fn test_new_cyclic() {
+    struct Node {
+        me: Weak<Node>,
+        value: i32,
+    }
+
+    let node = Arc::new_cyclic(|me| {
+        // there is no value to upgrade to yet
+        assert!(me.upgrade().is_none());
+        Node {
+            me: me.clone(),
+            value: 1,
+        }
+    });
+
+    assert_eq!(node.me.upgrade().unwrap().value, 1);
+    assert_eq!(Arc::strong_count(&node), 1);
+    assert_eq!(Arc::weak_count(&node), 1);
+}
+
+#[test]
+fn test_refcount_overflow() {
+    use std::{env, process::Command};
+
+    // counts right at the limit, as if that many clones had been forgotten
+    fn near_limit() -> (Arc<()>, Weak<()>) {
+        let a = Arc::new(());
+        let w = Arc::downgrade(&a);
+        a.data()
+            .data_ref_count
+            .store(MAX_REFCOUNT, Ordering::Relaxed);
+        a.data()
+            .alloc_ref_count
+            .store(MAX_REFCOUNT, Ordering::Relaxed);
+        (a, w)
+    }
+
+    type Overflow = fn(&Arc<()>, &Weak<()>);
+
+    // each of these goes past the limit, run in a child process that aborts
+    let overflows: [Overflow; 4] = [
+        |a, _| mem::forget(a.clone()),
+        |a, _| mem::forget(Arc::downgrade(a)),
+        |_, w| mem::forget(w.clone()),
+        |_, w| mem::forget(w.upgrade()),
+    ];
+    if let Ok(i) = env::var("ARC_OVERFLOW_CASE") {
+        let (a, w) = near_limit();
+        // the last one that fits
+        mem::forget(a.clone());
+        mem::forget(w.clone());
+        overflows[i.parse::<usize>().unwrap()](&a, &w);
+        unreachable!("the count went past the limit without aborting");
+    }
+
+    for i in 0..overflows.len() {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "arc::test_refcount_overflow", "--test-threads=1"])
+            .env("ARC_OVERFLOW_CASE", i.to_string())
+            .output()
+            .unwrap()
+            .status;
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            assert_eq!(status.signal(), Some(6), "case {i}: {status}");
+        }
+        assert!(!status.success(), "case {i}: {status}");
+    }
+}
+
+#[test]
+fn test_unsized_arc() {
+    use std::fmt::Display;
+
+    let s: Arc<str> = Arc::from("hello");
+    let t = s.clone();
+    assert_eq!(&*t, "hello");
+    assert_eq!(Arc::strong_count(&s), 2);
+    let w = Arc::downgrade(&s);
+    drop(s);
+    assert_eq!(&*w.upgrade().unwrap(), "hello");
+    drop(t);
+    assert!(w.upgrade().is_none());
+
+    // values that need dropping are moved, not copied
+    let items: Arc<[String]> = (0..3).map(|i| i.to_string()).collect();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[2], "2");
+    let items: Arc<[String]> = Arc::from(vec![String::from("a")]);
+    assert_eq!(*items, [String::from("a")]);
+
+    // more than the counts' alignment
+    #[repr(align(64))]
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    struct Aligned(u8);
+    let aligned: Arc<[Aligned]> = Arc::from(&[Aligned(1), Aligned(2)][..]);
+    assert_eq!(aligned.as_ptr() as usize % 64, 0);
+    let raw = Arc::into_raw(aligned);
+    let aligned = unsafe { Arc::from_raw(raw) };
+    assert_eq!(*aligned, [Aligned(1), Aligned(2)]);
+
+    let shown: Arc<dyn Display + Send + Sync> = Arc::new(1);
+    assert_eq!(shown.to_string(), "1");
+    let array: Arc<[i32]> = Arc::new([1, 2, 3]);
+    assert_eq!(array.iter().sum::<i32>(), 6);
+    let weak: Weak<dyn Display> = Weak::<i32>::new();
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_downcast() {
+    let any: Arc<dyn Any + Send + Sync> = Arc::new(String::from("a"));
+    let any = any.downcast::<i32>().unwrap_err();
+    let other = any.clone();
+    let s = any.downcast::<String>().unwrap();
+    assert_eq!(*s, "a");
+    assert_eq!(Arc::strong_count(&s), 2);
+    drop(other);
+    assert_eq!(Arc::try_unwrap(s).unwrap(), "a");
+}
+
+#[test]
+fn test_arc_alloc() {
+    use crate::carton::CountingAlloc;
+
+    let counting = CountingAlloc::default();
+
+    // the memory outlives the value while there are weak pointers
+    let a = Arc::new_in(String::from("a"), &counting);
+    let b = a.clone();
+    let w = Arc::downgrade(&a);
+    drop((a, b));
+    assert!(w.upgrade().is_none());
+    assert_eq!(counting.live(), 1);
+    drop(w);
+    assert_eq!(counting.live(), 0);
+
+    // make_mut moves the value away from the weak pointer, into a new allocation
+    let mut a = Arc::new_in(1, &counting);
+    let w = Arc::downgrade(&a);
+    *Arc::make_mut(&mut a) += 1;
+    assert_eq!(counting.live(), 2);
+    drop(w);
+    assert_eq!(Arc::try_unwrap(a).unwrap(), 2);
+
+    let any: Arc<dyn Any + Send + Sync, _> = Arc::new_in(1u8, &counting);
+    let n = any.downcast::<u8>().unwrap();
+    assert_eq!(Arc::into_inner(n), Some(1));
+
+    assert_eq!(counting.allocations(), 4);
+    assert_eq!(counting.live(), 0);
+
+    let full = CountingAlloc::with_limit(0);
+    assert_eq!(Arc::try_new_in(1, &full).err(), Some(AllocError));
+}
+
+// The behaviour `arc::Arc` and `rc::Rc` have in common, run against both. Each
+// module invokes it with the name of its pointer, both call theirs `Weak`.
+#[cfg(test)]
+macro_rules! shared_tests {
+    ($rc:ident) => {
+        mod shared {
+            use super::{$rc, Weak};
+            use crate::carton::CountingAlloc;
+            use std::{cell::Cell, fmt::Display};
+
+            struct DetectDrop<'a>(&'a Cell<usize>);
+
+            impl Drop for DetectDrop<'_> {
+                fn drop(&mut self) {
+                    self.0.set(self.0.get() + 1);
+                }
+            }
+
+            #[test]
+            fn test_drop_once() {
+                let drops = Cell::new(0);
+                let a = $rc::new(DetectDrop(&drops));
+                let b = a.clone();
+                let w = $rc::downgrade(&a);
+
+                drop(a);
+                assert!(w.upgrade().is_some());
+                drop(b);
+                assert_eq!(drops.get(), 1);
+                assert!(w.upgrade().is_none());
+                drop(w);
+                assert_eq!(drops.get(), 1);
+            }
+
+            #[test]
+            fn test_get_mut() {
+                let mut a = $rc::new(1);
+                *$rc::get_mut(&mut a).unwrap() += 1;
+
+                let b = a.clone();
+                assert!($rc::get_mut(&mut a).is_none());
+                drop(b);
+
+                let w = $rc::downgrade(&a);
+                assert!($rc::get_mut(&mut a).is_none());
+                drop(w);
+                assert_eq!($rc::get_mut(&mut a), Some(&mut 2));
+            }
+
+            #[test]
+            fn test_make_mut() {
+                // shared, so it's cloned
+                let mut a = $rc::new(1);
+                let b = a.clone();
+                *$rc::make_mut(&mut a) += 1;
+                assert_eq!((*a, *b), (2, 1));
+
+                // weak pointers stay with the old allocation
+                let w = $rc::downgrade(&a);
+                *$rc::make_mut(&mut a) += 1;
+                assert_eq!(*a, 3);
+                assert!(w.upgrade().is_none());
+
+                // unique, changed in place
+                let p = $rc::as_ptr(&a);
+                *$rc::make_mut(&mut a) += 1;
+                assert_eq!($rc::as_ptr(&a), p);
+                assert_eq!(*a, 4);
+            }
+
+            #[test]
+            fn test_unwrap_and_counts() {
+                let a = $rc::new(String::from("a"));
+                let b = a.clone();
+                let w = $rc::downgrade(&a);
+                assert_eq!(($rc::strong_count(&a), $rc::weak_count(&a)), (2, 1));
+                assert!($rc::ptr_eq(&a, &b));
+
+                let a = $rc::try_unwrap(a).unwrap_err();
+                assert_eq!($rc::into_inner(a), None);
+                assert_eq!($rc::try_unwrap(b).unwrap(), "a");
+                assert!(w.upgrade().is_none());
+
+                let raw = $rc::into_raw($rc::new(5));
+                let a = unsafe { $rc::from_raw(raw) };
+                assert_eq!($rc::into_inner(a), Some(5));
+                assert!(Weak::<i32>::new().upgrade().is_none());
+            }
+
+            #[test]
+            fn test_cyclic() {
+                struct Node {
+                    me: Weak<Node>,
+                }
+
+                let node = $rc::new_cyclic(|me| {
+                    assert!(me.upgrade().is_none());
+                    Node { me: me.clone() }
+                });
+                assert!($rc::ptr_eq(&node.me.upgrade().unwrap(), &node));
+            }
+
+            #[test]
+            fn test_unsized() {
+                let s: $rc<str> = $rc::from("hello");
+                assert_eq!(&*s.clone(), "hello");
+
+                let items: $rc<[String]> = (0..3).map(|i| i.to_string()).collect();
+                assert_eq!(items[2], "2");
+
+                let shown: $rc<dyn Display> = $rc::new(1);
+                assert_eq!(shown.to_string(), "1");
+                let weak: Weak<dyn Display> = $rc::downgrade(&shown);
+                drop(shown);
+                assert!(weak.upgrade().is_none());
+            }
+
+            #[test]
+            fn test_alloc() {
+                let counting = CountingAlloc::default();
+
+                let mut a = $rc::new_in(String::from("a"), &counting);
+                let w = $rc::downgrade(&a);
+                $rc::make_mut(&mut a).push('b');
+                assert_eq!(counting.live(), 2);
+                drop(w);
+                assert_eq!($rc::try_unwrap(a).unwrap(), "ab");
+                assert_eq!((counting.allocations(), counting.live()), (2, 0));
+
+                let full = CountingAlloc::with_limit(0);
+                assert!($rc::try_new_in(1, &full).is_err());
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+pub(crate) use shared_tests;
+
+#[cfg(test)]
+shared_tests!(Arc);
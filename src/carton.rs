@@ -1,11 +1,48 @@
 use std::{
+    alloc::{self, AllocError, Allocator, Layout},
     cmp::max,
-    mem::{align_of, size_of},
+    mem::size_of,
     ops::{Deref, DerefMut},
     ptr,
 };
 
-pub struct Carton<T>(ptr::NonNull<T>);
+pub struct Carton<T, A: Allocator = PosixMemalign>(ptr::NonNull<T>, A);
+
+/// Memory straight from libc, what `Carton` allocates with unless told otherwise.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PosixMemalign;
+
+unsafe impl Allocator for PosixMemalign {
+    fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        // posix_memalign may return null for zero bytes, any aligned address will do
+        if layout.size() == 0 {
+            let dangling = ptr::NonNull::new(ptr::without_provenance_mut(layout.align())).unwrap();
+            return Ok(ptr::NonNull::slice_from_raw_parts(dangling, 0));
+        }
+
+        let mut memptr: *mut u8 = ptr::null_mut();
+        let ret = unsafe {
+            libc::posix_memalign(
+                (&mut memptr as *mut *mut u8).cast(),
+                // has to be a multiple of the pointer size
+                max(layout.align(), size_of::<usize>()),
+                layout.size(),
+            )
+        };
+        if ret != 0 {
+            return Err(AllocError);
+        }
+
+        let ptr = ptr::NonNull::new(memptr).ok_or(AllocError)?;
+        Ok(ptr::NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            libc::free(ptr.as_ptr().cast())
+        }
+    }
+}
 
 // see: https://doc.rust-lang.org/nomicon/send-and-sync.html
 // 一句话解释什么是 Send 和 Sync
@@ -20,36 +57,40 @@ pub struct Carton<T>(ptr::NonNull<T>);
 impl<T> Carton<T> {
     #[allow(dead_code)]
     pub fn new(value: T) -> Self {
-        // 在堆上分配足够的内存给 T
-        assert_ne!(
-            0,
-            size_of::<T>(),
-            "zero-sized tpyes are out of scope of this example"
-        );
+        Self::new_in(value, PosixMemalign)
+    }
 
-        let mut memptr: *mut T = ptr::null_mut();
-        unsafe {
-            let ret = libc::posix_memalign(
-                (&mut memptr as *mut *mut T).cast(),
-                max(align_of::<T>(), size_of::<usize>()),
-                size_of::<T>(),
-            );
-            assert_eq!(ret, 0, "Failed to allocate or invalid alignment!");
+    #[allow(dead_code)]
+    pub fn try_new(value: T) -> Result<Self, AllocError> {
+        Self::try_new_in(value, PosixMemalign)
+    }
+}
+
+impl<T, A: Allocator> Carton<T, A> {
+    #[allow(dead_code)]
+    pub fn new_in(value: T, alloc: A) -> Self {
+        match Self::try_new_in(value, alloc) {
+            Ok(carton) => carton,
+            Err(AllocError) => alloc::handle_alloc_error(Layout::new::<T>()),
         }
+    }
 
-        let ptr =
-            { ptr::NonNull::new(memptr).expect("Guaranteed non-null if posix_memalign returns 0") };
+    /// Fails if the allocator is out of memory, `value` is dropped then.
+    #[allow(dead_code)]
+    pub fn try_new_in(value: T, alloc: A) -> Result<Self, AllocError> {
+        // 在堆上分配足够的内存给 T
+        let ptr = alloc.allocate(Layout::new::<T>())?.cast::<T>();
 
         // 将值从栈移动到堆中指向的区域
         unsafe {
             ptr.as_ptr().write(value);
         }
 
-        Self(ptr)
+        Ok(Self(ptr, alloc))
     }
 }
 
-impl<T> Deref for Carton<T> {
+impl<T, A: Allocator> Deref for Carton<T, A> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -57,7 +98,7 @@ impl<T> Deref for Carton<T> {
     }
 }
 
-impl<T> DerefMut for Carton<T> {
+impl<T, A: Allocator> DerefMut for Carton<T, A> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { self.0.as_mut() }
     }
@@ -67,7 +108,7 @@ impl<T> DerefMut for Carton<T> {
 // Carton to another thread if T can be safely transferred.
 // 除了一个变量没有互斥锁的保证下和其它变量共享一个可修改的状态值的情况外，它都可以安全的转为 Send
 // 由于 Carton 独享 raw_pointer 的值，所以不需要担心和其它变量共享这个指针，此时可以安全的转换为 Send
-unsafe impl<T> Send for Carton<T> where T: Send {}
+unsafe impl<T, A: Allocator + Send> Send for Carton<T, A> where T: Send {}
 
 // Safety: Since there exists a public way to go from a `&Carton<T>` to a `&T`
 // in an unsynchronized fashion (such as `Deref`), then `Carton<T>` can't be
@@ -80,10 +121,97 @@ unsafe impl<T> Send for Carton<T> where T: Send {}
 // 在我们的例子中，有一个非同步的API方法可以从 &Carton<T> 转换为 &T, 所以 &Carton<T> 要变为 Sync 的前提是 &T 是 Sync。
 // 其次，Carton 并未使用任何 interior mutablity(内部可变性，使用它可以允许同时存在多个可写引用)，也就是说针对它的所有修改都必须通过
 // &mut，这意味着只要 T 是 Sync，那么 Carton<T> 也可以是 Sync.
-unsafe impl<T> Sync for Carton<T> where T: Sync {}
+unsafe impl<T, A: Allocator + Sync> Sync for Carton<T, A> where T: Sync {}
 
-impl<T> Drop for Carton<T> {
+impl<T, A: Allocator> Drop for Carton<T, A> {
     fn drop(&mut self) {
-        unsafe { libc::free(self.0.as_ptr().cast()) }
+        unsafe {
+            ptr::drop_in_place(self.0.as_ptr());
+            self.1.deallocate(self.0.cast(), Layout::new::<T>());
+        }
     }
 }
+
+/// Counts what goes through it, and checks every allocation is freed exactly once.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct CountingAlloc {
+    // the addresses handed out and not freed yet
+    live: std::sync::Mutex<Vec<usize>>,
+    allocations: std::sync::atomic::AtomicUsize,
+    // fails once this many allocations were made
+    limit: Option<usize>,
+}
+
+#[cfg(test)]
+impl CountingAlloc {
+    pub(crate) fn with_limit(limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn allocations(&self) -> usize {
+        self.allocations.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn live(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+unsafe impl Allocator for CountingAlloc {
+    fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        use std::sync::atomic::Ordering;
+
+        if self.limit == Some(self.allocations()) {
+            return Err(AllocError);
+        }
+        let ptr = alloc::Global.allocate(layout)?;
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.live
+            .lock()
+            .unwrap()
+            .push(ptr.cast::<u8>().as_ptr() as usize);
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        let mut live = self.live.lock().unwrap();
+        let i = live
+            .iter()
+            .position(|&p| p == ptr.as_ptr() as usize)
+            .expect("freed memory that isn't allocated");
+        live.swap_remove(i);
+        alloc::Global.deallocate(ptr, layout);
+    }
+}
+
+#[test]
+fn test_carton() {
+    use std::rc::Rc;
+
+    let counting = CountingAlloc::default();
+    let value = Rc::new(());
+
+    let mut carton = Carton::new_in((value.clone(), 1u64), &counting);
+    // `.1` would be the allocator
+    (*carton).1 += 1;
+    assert_eq!((*carton).1, 2);
+    assert_eq!(counting.live(), 1);
+    // the value is dropped with the memory
+    drop(carton);
+    assert_eq!(counting.live(), 0);
+    assert_eq!(Rc::strong_count(&value), 1);
+
+    let full = CountingAlloc::with_limit(0);
+    assert!(Carton::try_new_in(value.clone(), &full).is_err());
+    assert_eq!(Rc::strong_count(&value), 1);
+
+    let carton = Carton::new([7u64; 4]);
+    assert_eq!(*carton, [7; 4]);
+    let carton = Carton::try_new(()).unwrap();
+    assert_eq!(*carton, ());
+}
@@ -0,0 +1,322 @@
+use super::lock_ignoring_poison;
+use crate::{futex, mutex::Mutex, rwlock::RwLock};
+use atomic_wait::wake_all;
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::SendError,
+        Arc, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+struct Channel<T> {
+    // message `pos` lives in `buffer[pos % buffer.len()]` until it's overwritten
+    buffer: Box<[RwLock<Slot<T>>]>,
+    tail: Mutex<Tail>,
+    // bumped after every message and when the last sender is gone, receivers sleep on it
+    version: AtomicU32,
+}
+
+struct Slot<T> {
+    pos: u64,
+    // None until the first message is written here
+    value: Option<T>,
+}
+
+struct Tail {
+    // the position of the next message
+    pos: u64,
+    senders: usize,
+    receivers: usize,
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// Receives every message sent after it subscribed, each receiver at its own pace.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    // the position of the next message this receiver sees
+    next: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders are gone and every message was received.
+    Closed,
+    /// The receiver fell behind, this many messages were overwritten before it
+    /// saw them. The next receive continues with the oldest message still there.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Closed,
+    Lagged(u64),
+}
+
+/// Creates a channel where every receiver gets every message. It keeps the last
+/// `cap` messages, a sender never waits for slow receivers, they lag instead.
+#[allow(dead_code)]
+pub fn broadcast<T: Clone>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(
+        cap > 0,
+        "a broadcast channel needs room for at least one message"
+    );
+
+    let channel = Arc::new(Channel {
+        buffer: (0..cap)
+            .map(|_| {
+                RwLock::new(Slot {
+                    pos: 0,
+                    value: None,
+                })
+            })
+            .collect(),
+        tail: Mutex::new(Tail {
+            pos: 0,
+            senders: 1,
+            receivers: 1,
+        }),
+        version: AtomicU32::new(0),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel, next: 0 },
+    )
+}
+
+impl<T> Channel<T> {
+    fn wake_receivers(&self) {
+        self.version.fetch_add(1, Ordering::Release);
+        wake_all(&self.version);
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Clone> Sender<T> {
+    /// Never blocks, returns how many receivers will see the message. Fails if
+    /// there are none.
+    pub fn send(&self, v: T) -> Result<usize, SendError<T>> {
+        let mut tail = lock_ignoring_poison(&self.channel.tail);
+        if tail.receivers == 0 {
+            return Err(SendError(v));
+        }
+
+        let buffer = &self.channel.buffer;
+        let mut slot = buffer[(tail.pos % buffer.len() as u64) as usize]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        slot.pos = tail.pos;
+        // the overwritten message is dropped after the locks are released
+        let old = slot.value.replace(v);
+        drop(slot);
+
+        tail.pos += 1;
+        let receivers = tail.receivers;
+        drop(tail);
+        drop(old);
+
+        self.channel.wake_receivers();
+        Ok(receivers)
+    }
+
+    /// A new receiver that sees the messages sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut tail = lock_ignoring_poison(&self.channel.tail);
+        tail.receivers += 1;
+        Receiver {
+            channel: self.channel.clone(),
+            next: tail.pos,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Clone> Receiver<T> {
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|e| match e {
+            RecvTimeoutError::Lagged(n) => RecvError::Lagged(n),
+            RecvTimeoutError::Closed | RecvTimeoutError::Timeout => RecvError::Closed,
+        })
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let buffer = &self.channel.buffer;
+        let slot = buffer[(self.next % buffer.len() as u64) as usize]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        match &slot.value {
+            Some(v) if slot.pos == self.next => {
+                let v = v.clone();
+                self.next += 1;
+                return Ok(v);
+            }
+            // overwritten by a message one or more rounds later
+            Some(_) if slot.pos > self.next => {
+                drop(slot);
+                let oldest = lock_ignoring_poison(&self.channel.tail).pos - buffer.len() as u64;
+                let missed = oldest - self.next;
+                self.next = oldest;
+                return Err(TryRecvError::Lagged(missed));
+            }
+            _ => {}
+        }
+        drop(slot);
+
+        // nothing new, look at the senders only now, so a message sent by
+        // the last one isn't missed
+        let tail = lock_ignoring_poison(&self.channel.tail);
+        if tail.senders == 0 && tail.pos == self.next {
+            Err(TryRecvError::Closed)
+        } else if tail.pos != self.next {
+            // sent between looking at the slot and the tail
+            drop(tail);
+            self.try_recv()
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            // read before looking, a message sent after that changes it
+            let version = self.channel.version.load(Ordering::Acquire);
+            match self.try_recv() {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Lagged(n)) => return Err(RecvTimeoutError::Lagged(n)),
+                Err(TryRecvError::Closed) => return Err(RecvTimeoutError::Closed),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            if !futex::wait_until(&self.channel.version, version, deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        lock_ignoring_poison(&self.channel.tail).senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+// the clone continues where this receiver is
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        lock_ignoring_poison(&self.channel.tail).receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut tail = lock_ignoring_poison(&self.channel.tail);
+        tail.senders -= 1;
+        if tail.senders == 0 {
+            drop(tail);
+            self.channel.wake_receivers();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        lock_ignoring_poison(&self.channel.tail).receivers -= 1;
+    }
+}
+
+#[test]
+fn test_broadcast() {
+    use std::thread;
+
+    let (sender, receiver) = broadcast(16);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let mut receiver = receiver.clone();
+            s.spawn(move || {
+                // every message is either received, in order, or counted as missed
+                let mut seen = 0;
+                let mut last = None;
+                loop {
+                    match receiver.recv() {
+                        Ok(v) => {
+                            assert!(last < Some(v));
+                            last = Some(v);
+                            seen += 1;
+                        }
+                        Err(RecvError::Lagged(n)) => seen += n,
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                assert_eq!(seen, 1000);
+            });
+        }
+        drop(receiver);
+
+        for i in 0..1000 {
+            sender.send(i).unwrap();
+        }
+        drop(sender);
+    });
+}
+
+#[test]
+fn test_broadcast_lagged() {
+    let (sender, mut receiver) = broadcast(4);
+
+    for i in 0..10 {
+        assert_eq!(sender.send(i), Ok(1));
+    }
+
+    // 0..6 were overwritten, 6..10 are still there
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Lagged(6)));
+    for i in 6..10 {
+        assert_eq!(receiver.try_recv(), Ok(i));
+    }
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(1)),
+        Err(RecvTimeoutError::Timeout)
+    );
+
+    // a new subscriber starts at the tail
+    let mut late = sender.subscribe();
+    sender.send(10).unwrap();
+    assert_eq!(late.try_recv(), Ok(10));
+    assert_eq!(receiver.try_recv(), Ok(10));
+
+    drop(sender);
+    assert_eq!(late.recv(), Err(RecvError::Closed));
+
+    drop(late);
+    let (sender, receiver) = broadcast(1);
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(SendError(1)));
+}
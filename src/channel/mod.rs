@@ -1,54 +1,167 @@
+use crate::{
+    futex,
+    mutex::{Mutex, MutexGuard},
+};
+use atomic_wait::wake_one;
+use select::{Selectable, Selectors};
 use std::{
     cell::UnsafeCell,
-    // collections::VecDeque,
-    marker::PhantomData,
     mem::MaybeUninit,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-        // Condvar, Mutex,
+        atomic::{AtomicU32, Ordering},
+        mpsc::{RecvError, RecvTimeoutError, TryRecvError},
+        Arc, PoisonError,
     },
-    thread::{self, Thread},
+    thread,
+    time::{Duration, Instant},
 };
 
+pub mod broadcast;
+pub mod mpmc;
+pub mod mpsc;
+pub mod rendezvous;
+pub mod select;
+pub mod watch;
+
+/// The error of a send with a timeout, the message is handed back either way.
+/// std only has this for its unstable mpmc channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    Timeout(T),
+    Disconnected(T),
+}
+
+// the channels never panic while holding their locks, so a poisoned lock only means
+// a panic somewhere else on the holding thread and the state is still fine to use.
+fn lock_ignoring_poison<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// the receiver waits on the state while it's EMPTY
+const EMPTY: u32 = 0;
+const READY: u32 = 1;
+// the sender was dropped without sending
+const SENDER_GONE: u32 = 2;
+// the receiver was dropped, sending fails
+const RECEIVER_GONE: u32 = 3;
+// the message was received
+const TAKEN: u32 = 4;
+
 struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
-    ready: AtomicBool,
+    state: AtomicU32,
+    selectors: Selectors,
 }
 
+/// Sends a single message, `send` takes the sender so it can't be called twice.
 pub struct Sender<T> {
     channel: Arc<Channel<T>>,
-    receiver_thread: Thread,
 }
 
+/// Receives the single message. Unlike the sender of the old channel, nothing
+/// is tied to the thread that created it, so it can be moved anywhere.
 pub struct Receiver<T> {
     channel: Arc<Channel<T>>,
-    _no_send: PhantomData<*const ()>,
 }
 
+#[allow(dead_code)]
 impl<T> Sender<T> {
-    /// Safety: Only call this once!
-    pub unsafe fn send(self, v: T) {
-        (*self.channel.message.get()).write(v);
-        self.channel.ready.store(true, Ordering::Release);
-        self.receiver_thread.unpark();
+    /// Fails and hands the message back if the receiver is gone.
+    pub fn send(self, v: T) -> Result<(), T> {
+        // the receiver doesn't look at the message until it's READY
+        unsafe { (*self.channel.message.get()).write(v) };
+
+        if let Err(_receiver_gone) =
+            self.channel
+                .state
+                .compare_exchange(EMPTY, READY, Ordering::Release, Ordering::Relaxed)
+        {
+            return Err(unsafe { (*self.channel.message.get()).assume_init_read() });
+        }
+
+        wake_one(&self.channel.state);
+        self.channel.selectors.notify();
+        Ok(())
     }
 }
 
+#[allow(dead_code)]
 impl<T> Receiver<T> {
-    /// Safety: Only call this once,
-    /// and only after is_ready() returns true!
-    pub unsafe fn receive(&self) -> T {
-        while !self.channel.ready.swap(false, Ordering::Acquire) {
-            // panic!("no message available!");
-            thread::park();
+    /// Blocks until the message arrives, fails if the sender was dropped without sending.
+    pub fn recv(self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    /// A message that was received already doesn't count, the channel is
+    /// disconnected then.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.channel.state.load(Ordering::Acquire) {
+            EMPTY => Err(TryRecvError::Empty),
+            READY => self.take().ok_or(TryRecvError::Disconnected),
+            _ => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.channel.state.load(Ordering::Acquire) {
+                READY => return self.take().ok_or(RecvTimeoutError::Disconnected),
+                EMPTY => {
+                    if !futex::wait_until(&self.channel.state, EMPTY, deadline) {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                }
+                _ => return Err(RecvTimeoutError::Disconnected),
+            }
+        }
+    }
+
+    // Threads sharing the receiver may all have seen READY, only the one that
+    // moves the state to TAKEN gets the message, the others find it received.
+    fn take(&self) -> Option<T> {
+        self.channel
+            .state
+            .compare_exchange(READY, TAKEN, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        self.channel.state.load(Ordering::Acquire) != EMPTY
+    }
+
+    fn selectors(&self) -> &Selectors {
+        &self.channel.selectors
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // fails if the message was sent or the receiver is gone
+        if self
+            .channel
+            .state
+            .compare_exchange(EMPTY, SENDER_GONE, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            wake_one(&self.channel.state);
+            self.channel.selectors.notify();
         }
-        (*self.channel.message.get()).assume_init_read()
     }
+}
 
-    // pub fn is_ready(&self) -> bool {
-    //     self.channel.ready.load(Ordering::Relaxed)
-    // }
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.state.swap(RECEIVER_GONE, Ordering::Acquire) == READY {
+            unsafe { (*self.channel.message.get()).assume_init_drop() }
+        }
+    }
 }
 
 unsafe impl<T> Sync for Channel<T> where T: Send {}
@@ -58,14 +171,16 @@ impl<T> Channel<T> {
     pub fn new() -> Self {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
-            ready: AtomicBool::new(false),
+            state: AtomicU32::new(EMPTY),
+            selectors: Selectors::default(),
         }
     }
 }
 
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
-        if *self.ready.get_mut() {
+        // the receiver drops a message it didn't take, unless it was leaked
+        if *self.state.get_mut() == READY {
             unsafe {
                 self.message.get_mut().assume_init_drop();
             }
@@ -73,39 +188,93 @@ impl<T> Drop for Channel<T> {
     }
 }
 
+/// A channel for a single message.
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
-    let a = Arc::new(Channel {
-        message: UnsafeCell::new(MaybeUninit::uninit()),
-        ready: AtomicBool::new(false),
-    });
+    let a = Arc::new(Channel::new());
 
-    (
-        Sender {
-            channel: a.clone(),
-            receiver_thread: thread::current(),
-        },
-        Receiver {
-            channel: a,
-            _no_send: PhantomData,
-        },
-    )
+    (Sender { channel: a.clone() }, Receiver { channel: a })
 }
 
 #[allow(dead_code)]
 pub fn channel_usage() {
     let (sender, receiver) = channel();
-    let t = thread::current();
     thread::scope(|s| {
         s.spawn(|| {
-            unsafe {
-                sender.send("hello cheng");
-                // sender.send("hello cheng");
-            }
-            t.unpark();
+            sender.send("hello cheng").unwrap();
+        });
+
+        // the receiver doesn't have to stay on the thread that created it
+        s.spawn(|| {
+            assert_eq!(receiver.recv(), Ok("hello cheng"));
         });
     });
+}
+
+#[test]
+fn test_oneshot() {
+    channel_usage();
+
+    // the sender was dropped without sending
+    let (sender, receiver) = channel::<i32>();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(1)),
+        Err(RecvTimeoutError::Timeout)
+    );
+    drop(sender);
+    assert_eq!(receiver.recv(), Err(RecvError));
+
+    // the receiver is gone, the message comes back
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(1));
+
+    let (sender, receiver) = channel();
+    sender.send(1).unwrap();
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_oneshot_drops_message() {
+    use std::rc::Rc;
+
+    // a message that is never received is dropped with the receiver
+    let message = Rc::new(());
+    let (sender, receiver) = channel();
+    // Rc isn't Send, but nothing leaves this thread
+    sender.send(message.clone()).unwrap();
+    assert_eq!(Rc::strong_count(&message), 2);
+    drop(receiver);
+    assert_eq!(Rc::strong_count(&message), 1);
+}
+
+#[test]
+fn test_oneshot_shared_receiver() {
+    use std::sync::Barrier;
+
+    // the receiver is shared by reference, exactly one thread gets each message
+    for _ in 0..1000 {
+        let (sender, receiver) = channel();
+        let start = Barrier::new(2);
+        sender.send(String::from("once")).unwrap();
+
+        let received = thread::scope(|s| {
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        start.wait();
+                        receiver.try_recv()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .collect::<Vec<_>>()
+        });
 
-    unsafe {
-        assert_eq!(receiver.receive(), "hello cheng");
+        assert!(received.contains(&Ok(String::from("once"))));
+        assert!(received.contains(&Err(TryRecvError::Disconnected)));
     }
 }
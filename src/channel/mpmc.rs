@@ -0,0 +1,340 @@
+use super::{
+    lock_ignoring_poison,
+    select::{Selectable, Selectors},
+    SendTimeoutError,
+};
+use crate::{cond_var::CondVar, mutex::Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError},
+        Arc, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+struct Channel<T> {
+    state: Mutex<State<T>>,
+    not_empty: CondVar,
+    not_full: CondVar,
+    cap: usize,
+    selectors: Selectors,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// Creates a channel that holds up to `cap` messages, `send` blocks while it's full.
+/// Both halves can be cloned, every message is received by exactly one receiver.
+#[allow(dead_code)]
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(
+        cap > 0,
+        "a bounded channel needs room for at least one message"
+    );
+
+    let channel = Arc::new(Channel {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(cap),
+            senders: 1,
+            receivers: 1,
+        }),
+        not_empty: CondVar::new(),
+        not_full: CondVar::new(),
+        cap,
+        selectors: Selectors::default(),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+impl<T> Channel<T> {
+    fn send_until(&self, v: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let mut state = lock_ignoring_poison(&self.state);
+        loop {
+            if state.receivers == 0 {
+                return Err(SendTimeoutError::Disconnected(v));
+            }
+
+            if state.queue.len() < self.cap {
+                state.queue.push_back(v);
+                drop(state);
+                self.not_empty.notify_one();
+                self.selectors.notify();
+                return Ok(());
+            }
+
+            state = match deadline {
+                None => self
+                    .not_full
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(SendTimeoutError::Timeout(v));
+                    }
+                    self.not_full
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut state = lock_ignoring_poison(&self.state);
+        loop {
+            // messages sent before the last sender left can still be received
+            if let Some(v) = state.queue.pop_front() {
+                drop(state);
+                self.not_full.notify_one();
+                self.selectors.notify();
+                return Ok(v);
+            }
+
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            state = match deadline {
+                None => self
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.not_empty
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Sender<T> {
+    /// Blocks while the channel is full, fails if all receivers are gone.
+    pub fn send(&self, v: T) -> Result<(), SendError<T>> {
+        self.channel.send_until(v, None).map_err(|e| match e {
+            SendTimeoutError::Disconnected(v) | SendTimeoutError::Timeout(v) => SendError(v),
+        })
+    }
+
+    pub fn try_send(&self, v: T) -> Result<(), TrySendError<T>> {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(v));
+        }
+        if state.queue.len() == self.channel.cap {
+            return Err(TrySendError::Full(v));
+        }
+
+        state.queue.push_back(v);
+        drop(state);
+        self.channel.not_empty.notify_one();
+        self.channel.selectors.notify();
+        Ok(())
+    }
+
+    pub fn send_timeout(&self, v: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        // too far in the future to ever time out
+        self.channel
+            .send_until(v, Instant::now().checked_add(timeout))
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Receiver<T> {
+    /// Blocks until a message arrives, fails once the channel is empty and all
+    /// senders are gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.channel.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        match state.queue.pop_front() {
+            Some(v) => {
+                drop(state);
+                self.channel.not_full.notify_one();
+                self.channel.selectors.notify();
+                Ok(v)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.channel.recv_until(Instant::now().checked_add(timeout))
+    }
+}
+
+impl<T> Selectable for Sender<T> {
+    fn is_ready(&self) -> bool {
+        let state = lock_ignoring_poison(&self.channel.state);
+        state.queue.len() < self.channel.cap || state.receivers == 0
+    }
+
+    fn selectors(&self) -> &Selectors {
+        &self.channel.selectors
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let state = lock_ignoring_poison(&self.channel.state);
+        !state.queue.is_empty() || state.senders == 0
+    }
+
+    fn selectors(&self) -> &Selectors {
+        &self.channel.selectors
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        lock_ignoring_poison(&self.channel.state).senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        lock_ignoring_poison(&self.channel.state).receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            // blocked receivers have to see the disconnection
+            self.channel.not_empty.notify_all();
+            self.channel.selectors.notify();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            drop(state);
+            self.channel.not_full.notify_all();
+            self.channel.selectors.notify();
+        }
+    }
+}
+
+#[test]
+fn test_mpmc() {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    let (sender, receiver) = bounded(4);
+    let sum = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 1..=1000 {
+                    sender.send(i).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        for _ in 0..4 {
+            let receiver = receiver.clone();
+            let sum = &sum;
+            s.spawn(move || {
+                while let Ok(i) = receiver.recv() {
+                    sum.fetch_add(i, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+
+    assert_eq!(sum.load(Ordering::Relaxed), 4 * 1000 * 1001 / 2);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_try_and_timeout() {
+    let (sender, receiver) = bounded(1);
+
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(1)),
+        Err(RecvTimeoutError::Timeout)
+    );
+
+    sender.try_send(1).unwrap();
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(
+        sender.send_timeout(2, Duration::from_millis(1)),
+        Err(SendTimeoutError::Timeout(2))
+    );
+
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Ok(1));
+    sender.send_timeout(2, Duration::from_secs(1)).unwrap();
+    assert_eq!(receiver.try_recv(), Ok(2));
+}
+
+#[test]
+fn test_disconnect() {
+    use std::thread;
+
+    // a blocked receiver wakes up when the last sender is gone
+    let (sender, receiver) = bounded::<i32>(1);
+    let sender2 = sender.clone();
+    thread::scope(|s| {
+        s.spawn(|| assert_eq!(receiver.recv(), Err(RecvError)));
+        drop(sender);
+        drop(sender2);
+    });
+
+    // a blocked sender gets its message back when the last receiver is gone
+    let (sender, receiver) = bounded(1);
+    sender.send(1).unwrap();
+    thread::scope(|s| {
+        s.spawn(|| assert_eq!(sender.send(2), Err(SendError(2))));
+        drop(receiver);
+    });
+    assert_eq!(sender.try_send(3), Err(TrySendError::Disconnected(3)));
+}
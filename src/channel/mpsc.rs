@@ -0,0 +1,363 @@
+use super::select::{Selectable, Selectors};
+use crate::futex;
+use atomic_wait::wake_one;
+use std::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+        mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// the receiver sleeps on `receiver_state` while it's SLEEPING
+const AWAKE: u32 = 0;
+const SLEEPING: u32 = 1;
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    // uninit for the stub node the receiver points at
+    value: MaybeUninit<T>,
+}
+
+// Vyukov's intrusive mpsc queue: senders swap themselves in at `head`, the single
+// receiver follows the `next` links from `tail`. `tail` always points at a stub
+// node whose value was taken already.
+struct Channel<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: UnsafeCell<*mut Node<T>>,
+    receiver_state: AtomicU32,
+    senders: AtomicUsize,
+    receiver_gone: AtomicBool,
+    selectors: Selectors,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+unsafe impl<T> Send for Channel<T> where T: Send {}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// There is only one receiver, it can be moved to another thread but not shared.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// Creates a channel without a limit on the number of queued messages, so
+/// sending never blocks.
+#[allow(dead_code)]
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    let stub = Box::into_raw(Box::new(Node {
+        next: AtomicPtr::new(ptr::null_mut()),
+        value: MaybeUninit::uninit(),
+    }));
+
+    let channel = Arc::new(Channel {
+        head: AtomicPtr::new(stub),
+        tail: UnsafeCell::new(stub),
+        receiver_state: AtomicU32::new(AWAKE),
+        senders: AtomicUsize::new(1),
+        receiver_gone: AtomicBool::new(false),
+        selectors: Selectors::default(),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver {
+            channel,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+impl<T> Channel<T> {
+    fn push(&self, v: T) {
+        let node = Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value: MaybeUninit::new(v),
+        }));
+
+        let prev = self.head.swap(node, Ordering::AcqRel);
+        // until this store the receiver can't see the node, nor the ones pushed after it
+        unsafe { (*prev).next.store(node, Ordering::Release) };
+    }
+
+    // Safety: only the receiver may call this.
+    //
+    // Also `None` while a sender swapped in a new head but hasn't linked it yet.
+    // Waiting for it could take as long as that sender is descheduled, instead it
+    // wakes the receiver once the message is linked, like after any other push.
+    unsafe fn pop(&self) -> Option<T> {
+        let tail = *self.tail.get();
+        let next = (*tail).next.load(Ordering::Acquire);
+        if next.is_null() {
+            return None;
+        }
+
+        *self.tail.get() = next;
+        drop(Box::from_raw(tail));
+        Some((*next).value.assume_init_read())
+    }
+
+    // after a message or the disconnection was published
+    fn wake_receiver(&self) {
+        // pairs with the fence in `recv_until`, either we see the receiver is
+        // going to sleep, or it sees what we published.
+        fence(Ordering::SeqCst);
+        if self.receiver_state.load(Ordering::Relaxed) == SLEEPING
+            && self.receiver_state.swap(AWAKE, Ordering::Relaxed) == SLEEPING
+        {
+            wake_one(&self.receiver_state);
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        let mut node = *self.tail.get_mut();
+        // the stub's value was taken already
+        let mut next = unsafe { Box::from_raw(node) }.next.into_inner();
+        while !next.is_null() {
+            node = next;
+            let mut boxed = unsafe { Box::from_raw(node) };
+            unsafe { boxed.value.assume_init_drop() };
+            next = boxed.next.into_inner();
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Sender<T> {
+    /// Never blocks, fails if the receiver is gone.
+    pub fn send(&self, v: T) -> Result<(), SendError<T>> {
+        // a message sent while the receiver leaves is dropped with the channel
+        if self.channel.receiver_gone.load(Ordering::Relaxed) {
+            return Err(SendError(v));
+        }
+
+        self.channel.push(v);
+        self.channel.wake_receiver();
+        self.channel.selectors.notify();
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Receiver<T> {
+    /// Blocks until a message arrives, fails once the queue is empty and all
+    /// senders are gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        // read before popping, so a message sent by the last sender isn't missed
+        let disconnected = self.channel.senders.load(Ordering::Acquire) == 0;
+        match unsafe { self.channel.pop() } {
+            Some(v) => Ok(v),
+            None if disconnected => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        // too far in the future to ever time out
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Blocks for every message, ends once all senders are gone.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+
+    /// Takes the messages that are queued right now, without blocking.
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { receiver: self }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let state = &self.channel.receiver_state;
+        loop {
+            match self.try_recv() {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            state.store(SLEEPING, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+
+            // look again, a sender may have missed that we are going to sleep
+            let r = match self.try_recv() {
+                Ok(v) => Ok(v),
+                Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {
+                    if futex::wait_until(state, SLEEPING, deadline) {
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout)
+                }
+            };
+
+            state.store(AWAKE, Ordering::Relaxed);
+            return r;
+        }
+    }
+}
+
+impl<T> Selectable for Receiver<T> {
+    // a message that is being linked in isn't ready yet, its sender notifies after
+    fn is_ready(&self) -> bool {
+        let tail = unsafe { *self.channel.tail.get() };
+        !unsafe { (*tail).next.load(Ordering::Acquire) }.is_null()
+            || self.channel.senders.load(Ordering::Acquire) == 0
+    }
+
+    fn selectors(&self) -> &Selectors {
+        &self.channel.selectors
+    }
+}
+
+pub struct Iter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+pub struct TryIter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::Release) == 1 {
+            self.channel.wake_receiver();
+            self.channel.selectors.notify();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_gone.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_mpsc_stress() {
+    use std::thread;
+
+    // small enough for miri
+    const SENDERS: usize = 4;
+    const MESSAGES: usize = if cfg!(miri) { 100 } else { 100_000 };
+
+    let (sender, receiver) = unbounded();
+
+    thread::scope(|s| {
+        for id in 0..SENDERS {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..MESSAGES {
+                    sender.send((id, i)).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        // the messages of each sender arrive in order
+        let mut next = [0; SENDERS];
+        for (id, i) in receiver.iter() {
+            assert_eq!(next[id], i);
+            next[id] += 1;
+        }
+        assert_eq!(next, [MESSAGES; SENDERS]);
+    });
+
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_mpsc() {
+    use std::rc::Rc;
+
+    let (sender, receiver) = unbounded();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(1)),
+        Err(RecvTimeoutError::Timeout)
+    );
+
+    for i in 0..3 {
+        sender.send(i).unwrap();
+    }
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(receiver.try_iter().next(), None);
+
+    // queued messages are dropped with the channel
+    let message = Rc::new(());
+    let (sender, receiver) = unbounded();
+    sender.send(message.clone()).unwrap();
+    sender.send(message.clone()).unwrap();
+    drop(receiver);
+    assert!(sender.send(message.clone()).is_err());
+    assert_eq!(Rc::strong_count(&message), 3);
+    drop(sender);
+    assert_eq!(Rc::strong_count(&message), 1);
+}
+
+#[test]
+fn test_mpsc_unlinked() {
+    use std::thread;
+
+    let (sender, receiver) = unbounded();
+
+    // a push stopped halfway, the new head isn't linked to the queue yet
+    let node = Box::into_raw(Box::new(Node {
+        next: AtomicPtr::new(ptr::null_mut()),
+        value: MaybeUninit::new(1),
+    }));
+    let prev = sender.channel.head.swap(node, Ordering::AcqRel);
+
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(receiver.try_iter().next(), None);
+
+    thread::scope(|s| {
+        s.spawn(move || assert_eq!(receiver.recv(), Ok(1)));
+
+        thread::sleep(Duration::from_millis(10));
+        // finish the push
+        unsafe { (*prev).next.store(node, Ordering::Release) };
+        sender.channel.wake_receiver();
+    });
+}
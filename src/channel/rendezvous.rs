@@ -0,0 +1,350 @@
+use super::{
+    lock_ignoring_poison,
+    select::{Selectable, Selectors},
+    SendTimeoutError,
+};
+use crate::{
+    cond_var::CondVar,
+    mutex::{Mutex, MutexGuard},
+};
+use std::{
+    sync::{
+        mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError},
+        Arc, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+struct Channel<T> {
+    state: Mutex<State<T>>,
+    // receivers wait for a value to be offered
+    offered: CondVar,
+    // senders wait for the slot to be free, or for their value to be taken
+    taken: CondVar,
+    selectors: Selectors,
+}
+
+struct State<T> {
+    // the value of the one sender that is waiting for a receiver
+    slot: Option<T>,
+    // number of values put into the slot and taken out by receivers, a sender
+    // knows its value was taken once `taken` reaches its turn in `offers`
+    offers: u64,
+    taken: u64,
+    senders: usize,
+    receivers: usize,
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// Creates a channel without any buffer, like `std::sync::mpsc::sync_channel(0)`:
+/// `send` blocks until a receiver took the value.
+#[allow(dead_code)]
+pub fn rendezvous<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        state: Mutex::new(State {
+            slot: None,
+            offers: 0,
+            taken: 0,
+            senders: 1,
+            receivers: 1,
+        }),
+        offered: CondVar::new(),
+        taken: CondVar::new(),
+        selectors: Selectors::default(),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+// waits on `cond_var`, hands the guard back as an error once the deadline has passed
+fn wait_until<'a, T>(
+    cond_var: &CondVar,
+    guard: MutexGuard<'a, State<T>>,
+    deadline: Option<Instant>,
+) -> Result<MutexGuard<'a, State<T>>, MutexGuard<'a, State<T>>> {
+    match deadline {
+        None => Ok(cond_var.wait(guard).unwrap_or_else(PoisonError::into_inner)),
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(guard);
+            }
+            let (guard, _) = cond_var
+                .wait_timeout(guard, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner);
+            Ok(guard)
+        }
+    }
+}
+
+impl<T> Channel<T> {
+    fn send_until(&self, v: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let mut state = lock_ignoring_poison(&self.state);
+
+        // another sender's value is waiting for a receiver
+        while state.slot.is_some() && state.receivers != 0 {
+            match wait_until(&self.taken, state, deadline) {
+                Ok(s) => state = s,
+                Err(_) => return Err(SendTimeoutError::Timeout(v)),
+            }
+        }
+        if state.receivers == 0 {
+            return Err(SendTimeoutError::Disconnected(v));
+        }
+
+        state.slot = Some(v);
+        state.offers += 1;
+        let ticket = state.offers;
+        self.offered.notify_one();
+        self.selectors.notify();
+
+        while state.taken < ticket {
+            if state.receivers == 0 {
+                return Err(SendTimeoutError::Disconnected(self.withdraw(state)));
+            }
+            match wait_until(&self.taken, state, deadline) {
+                Ok(s) => state = s,
+                Err(s) => return Err(SendTimeoutError::Timeout(self.withdraw(s))),
+            }
+        }
+
+        Ok(())
+    }
+
+    // takes back the value of a sender that gives up, nobody took it, so it's
+    // still in the slot. Other senders may use the slot then.
+    fn withdraw(&self, mut state: MutexGuard<'_, State<T>>) -> T {
+        let v = state.slot.take().unwrap();
+        state.offers -= 1;
+        drop(state);
+        self.taken.notify_all();
+        v
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut state = lock_ignoring_poison(&self.state);
+        loop {
+            if let Some(v) = self.take(&mut state) {
+                drop(state);
+                self.taken.notify_all();
+                return Ok(v);
+            }
+
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            match wait_until(&self.offered, state, deadline) {
+                Ok(s) => state = s,
+                Err(_) => return Err(RecvTimeoutError::Timeout),
+            }
+        }
+    }
+
+    fn take(&self, state: &mut State<T>) -> Option<T> {
+        let v = state.slot.take()?;
+        state.taken += 1;
+        Some(v)
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Sender<T> {
+    /// Blocks until a receiver took the value, fails if all receivers are gone.
+    pub fn send(&self, v: T) -> Result<(), SendError<T>> {
+        self.channel.send_until(v, None).map_err(|e| match e {
+            SendTimeoutError::Disconnected(v) | SendTimeoutError::Timeout(v) => SendError(v),
+        })
+    }
+
+    /// Gives up if no receiver took the value in time, the value is handed back then.
+    pub fn send_timeout(&self, v: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.channel
+            .send_until(v, Instant::now().checked_add(timeout))
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.channel.recv_until(None).map_err(|_| RecvError)
+    }
+
+    /// Only succeeds if a sender is blocked in `send` right now.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        match self.channel.take(&mut state) {
+            Some(v) => {
+                drop(state);
+                self.channel.taken.notify_all();
+                Ok(v)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.channel.recv_until(Instant::now().checked_add(timeout))
+    }
+}
+
+// only receivers can be selected, a sender has nothing to wait for before it blocks
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let state = lock_ignoring_poison(&self.channel.state);
+        state.slot.is_some() || state.senders == 0
+    }
+
+    fn selectors(&self) -> &Selectors {
+        &self.channel.selectors
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        lock_ignoring_poison(&self.channel.state).senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        lock_ignoring_poison(&self.channel.state).receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.channel.offered.notify_all();
+            self.channel.selectors.notify();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = lock_ignoring_poison(&self.channel.state);
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            drop(state);
+            // a waiting sender gets its value back
+            self.channel.taken.notify_all();
+        }
+    }
+}
+
+#[test]
+fn test_rendezvous() {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    let (sender, receiver) = rendezvous();
+    let received = AtomicBool::new(false);
+
+    // send only returns after the value was taken
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(20));
+            received.store(true, Ordering::Relaxed);
+            assert_eq!(receiver.recv(), Ok(1));
+        });
+
+        sender.send(1).unwrap();
+        assert!(received.load(Ordering::Relaxed));
+    });
+
+    // without a receiver, the value comes back
+    assert_eq!(
+        sender.send_timeout(2, Duration::from_millis(1)),
+        Err(SendTimeoutError::Timeout(2))
+    );
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(1)),
+        Err(RecvTimeoutError::Timeout)
+    );
+}
+
+#[test]
+fn test_rendezvous_many() {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    let (sender, receiver) = rendezvous();
+    let sum = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 1..=100 {
+                    // some of them time out and try again
+                    let mut v = i;
+                    while let Err(SendTimeoutError::Timeout(back)) =
+                        sender.send_timeout(v, Duration::from_micros(50))
+                    {
+                        v = back;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for _ in 0..2 {
+            let receiver = receiver.clone();
+            let sum = &sum;
+            s.spawn(move || {
+                while let Ok(i) = receiver.recv() {
+                    sum.fetch_add(i, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+
+    assert_eq!(sum.load(Ordering::Relaxed), 4 * 100 * 101 / 2);
+}
+
+#[test]
+fn test_rendezvous_disconnect() {
+    use std::thread;
+
+    let (sender, receiver) = rendezvous::<i32>();
+    thread::scope(|s| {
+        s.spawn(|| assert_eq!(receiver.recv(), Err(RecvError)));
+        drop(sender);
+    });
+
+    // a blocked sender gets its value back when the last receiver is gone
+    let (sender, receiver) = rendezvous();
+    thread::scope(|s| {
+        s.spawn(|| assert_eq!(sender.send(1), Err(SendError(1))));
+        thread::sleep(Duration::from_millis(10));
+        drop(receiver);
+    });
+}
@@ -0,0 +1,252 @@
+use crate::{futex, mutex::Mutex};
+use atomic_wait::wake_one;
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{fence, AtomicU32, AtomicUsize, Ordering},
+        Arc, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// An operation a `Select` can wait for, like receiving from a channel.
+pub trait Selectable {
+    /// Whether the operation wouldn't block right now. A disconnected channel
+    /// counts as ready, the operation fails right away then.
+    fn is_ready(&self) -> bool;
+
+    /// Where a blocked `Select` registers to hear about changes.
+    fn selectors(&self) -> &Selectors;
+}
+
+/// The blocked `Select`s waiting for a channel. Every channel has one and calls
+/// `notify` after anything changed that could make one of its operations ready.
+pub struct Selectors {
+    // each select sleeps on its own counter
+    waiting: Mutex<Vec<Arc<AtomicU32>>>,
+    // the length of `waiting`, so channels don't need the lock while nobody selects
+    count: AtomicUsize,
+}
+
+impl Default for Selectors {
+    fn default() -> Self {
+        Self {
+            waiting: Mutex::new(Vec::new()),
+            count: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Selectors {
+    fn register(&self, signal: &Arc<AtomicU32>) {
+        let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        waiting.push(signal.clone());
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn unregister(&self, signal: &Arc<AtomicU32>) {
+        let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(i) = waiting.iter().position(|s| Arc::ptr_eq(s, signal)) {
+            waiting.swap_remove(i);
+            self.count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn notify(&self) {
+        // pairs with the fence in `Select::ready_until`, either we see the select
+        // registered, or it sees what changed.
+        fence(Ordering::SeqCst);
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        for signal in waiting.iter() {
+            signal.fetch_add(1, Ordering::Release);
+            wake_one(&**signal);
+        }
+    }
+}
+
+/// Waits until one of several operations is ready, e.g. the first of a few
+/// receivers that got a message, see `ready`.
+#[derive(Default)]
+pub struct Select<'a> {
+    operations: Vec<&'a dyn Selectable>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryReadyError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadyTimeoutError;
+
+#[allow(dead_code)]
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an operation, returns the index `ready` reports it with.
+    pub fn add(&mut self, operation: &'a dyn Selectable) -> usize {
+        self.operations.push(operation);
+        self.operations.len() - 1
+    }
+
+    /// Blocks until one of the operations is ready and returns its index. If
+    /// several are ready, a random one is picked, so none of them starves.
+    ///
+    /// Another thread may still get there first, e.g. receive the message from
+    /// a shared receiver, so the operation has to be tried without blocking,
+    /// and on failure, `ready` called again.
+    pub fn ready(&mut self) -> usize {
+        self.ready_until(None).unwrap()
+    }
+
+    pub fn try_ready(&mut self) -> Result<usize, TryReadyError> {
+        self.find_ready().ok_or(TryReadyError)
+    }
+
+    pub fn ready_timeout(&mut self, timeout: Duration) -> Result<usize, ReadyTimeoutError> {
+        self.ready_until(Instant::now().checked_add(timeout))
+    }
+
+    fn ready_until(&self, deadline: Option<Instant>) -> Result<usize, ReadyTimeoutError> {
+        assert!(!self.operations.is_empty(), "no operations to select from");
+
+        if let Some(i) = self.find_ready() {
+            return Ok(i);
+        }
+
+        let signal = Arc::new(AtomicU32::new(0));
+        for operation in &self.operations {
+            operation.selectors().register(&signal);
+        }
+
+        let r = loop {
+            let counter = signal.load(Ordering::Acquire);
+            fence(Ordering::SeqCst);
+            if let Some(i) = self.find_ready() {
+                break Ok(i);
+            }
+
+            if !futex::wait_until(&signal, counter, deadline) {
+                break Err(ReadyTimeoutError);
+            }
+        };
+
+        for operation in &self.operations {
+            operation.selectors().unregister(&signal);
+        }
+
+        r
+    }
+
+    // starts looking at a random operation, so the first ones aren't preferred
+    fn find_ready(&self) -> Option<usize> {
+        let n = self.operations.len();
+        let start = random() % n;
+        (start..n)
+            .chain(0..start)
+            .find(|&i| self.operations[i].is_ready())
+    }
+}
+
+// xorshift, good enough to pick where to start
+fn random() -> usize {
+    thread_local! {
+        // std's hash maps are seeded randomly, borrow that instead of a rand crate
+        static STATE: Cell<u32> = Cell::new(RandomState::new().build_hasher().finish() as u32 | 1);
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        state.set(x);
+        x as usize
+    })
+}
+
+#[test]
+fn test_select() {
+    use super::{mpmc, mpsc, rendezvous};
+    use std::{sync::mpsc::TryRecvError, thread};
+
+    let (control_sender, control) = mpmc::bounded::<&str>(1);
+    let (data_sender, data) = mpsc::unbounded();
+    let (_handoff_sender, handoff) = rendezvous::rendezvous::<i32>();
+
+    let mut select = Select::new();
+    let control_i = select.add(&control);
+    let data_i = select.add(&data);
+    select.add(&handoff);
+
+    assert_eq!(select.try_ready(), Err(TryReadyError));
+    assert_eq!(
+        select.ready_timeout(Duration::from_millis(1)),
+        Err(ReadyTimeoutError)
+    );
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..100 {
+                data_sender.send(i).unwrap();
+            }
+            thread::sleep(Duration::from_millis(10));
+            control_sender.send("stop").unwrap();
+        });
+
+        let mut received = 0;
+        loop {
+            let i = select.ready();
+            if i == control_i {
+                assert_eq!(control.try_recv(), Ok("stop"));
+                break;
+            }
+
+            assert_eq!(i, data_i);
+            assert_eq!(data.try_recv(), Ok(received));
+            received += 1;
+        }
+        assert_eq!(received, 100);
+        assert_eq!(data.try_recv(), Err(TryRecvError::Empty));
+    });
+}
+
+#[test]
+fn test_select_fair() {
+    use super::mpmc;
+
+    let (sender1, receiver1) = mpmc::bounded(1);
+    let (sender2, receiver2) = mpmc::bounded(1);
+    sender1.send(1).unwrap();
+    sender2.send(2).unwrap();
+
+    // both are ready all the time, each should be picked now and then
+    let mut select = Select::new();
+    select.add(&receiver1);
+    select.add(&receiver2);
+    let mut picked = [0; 2];
+    for _ in 0..1000 {
+        picked[select.ready()] += 1;
+    }
+    assert!(picked[0] > 100 && picked[1] > 100, "{picked:?}");
+
+    // a sender of a bounded channel is ready while there is room
+    let mut select = Select::new();
+    select.add(&sender1);
+    assert_eq!(select.try_ready(), Err(TryReadyError));
+    assert_eq!(receiver1.try_recv(), Ok(1));
+    assert_eq!(select.try_ready(), Ok(0));
+
+    // disconnection counts as ready
+    let mut select = Select::new();
+    select.add(&receiver2);
+    assert_eq!(receiver2.try_recv(), Ok(2));
+    drop(sender2);
+    assert_eq!(select.ready(), 0);
+}
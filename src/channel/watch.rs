@@ -0,0 +1,231 @@
+use crate::rwlock::{ReadGuide, RwLock};
+use atomic_wait::{wait, wake_all};
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        mpsc::{RecvError, SendError},
+        Arc, PoisonError,
+    },
+};
+
+// the version goes up in steps of 2, the lowest bit is set once the sender is gone
+const CLOSED: u32 = 1;
+
+struct Channel<T> {
+    value: RwLock<T>,
+    // receivers sleep on it until it changes, like `RwLock::writer_wake_counter`
+    version: AtomicU32,
+    receivers: AtomicUsize,
+}
+
+/// Replaces the value, there is only one sender.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// Looks at the latest value and waits for it to change.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    // the version of the value this receiver has seen, without the CLOSED bit
+    seen: u32,
+}
+
+/// Creates a channel that holds a single value, receivers only ever see the
+/// latest one.
+#[allow(dead_code)]
+pub fn watch<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        value: RwLock::new(init),
+        version: AtomicU32::new(0),
+        receivers: AtomicUsize::new(1),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel, seen: 0 },
+    )
+}
+
+#[allow(dead_code)]
+impl<T> Sender<T> {
+    /// Fails and hands the value back if there are no receivers left.
+    pub fn send(&self, v: T) -> Result<(), SendError<T>> {
+        if self.channel.receivers.load(Ordering::Relaxed) == 0 {
+            return Err(SendError(v));
+        }
+
+        self.send_replace(v);
+        Ok(())
+    }
+
+    /// Replaces the value even if nobody is watching, returns the old one.
+    pub fn send_replace(&self, v: T) -> T {
+        let mut old = v;
+        self.send_modify(|value| mem::swap(value, &mut old));
+        old
+    }
+
+    /// Changes the value in place, receivers see it as a new version.
+    pub fn send_modify(&self, modify: impl FnOnce(&mut T)) {
+        let mut value = self
+            .channel
+            .value
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        modify(&mut value);
+        // still under the write lock, so a reader sees the version that goes with the value
+        self.channel.version.fetch_add(2, Ordering::Release);
+        drop(value);
+
+        wake_all(&self.channel.version);
+    }
+
+    pub fn borrow(&self) -> ReadGuide<'_, T> {
+        self.channel
+            .value
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A new receiver, the current value counts as seen.
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.channel.receivers.fetch_add(1, Ordering::Relaxed);
+        Receiver {
+            channel: self.channel.clone(),
+            seen: self.channel.version.load(Ordering::Acquire) & !CLOSED,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Receiver<T> {
+    /// The latest value, without marking it as seen. Holding on to it blocks the sender.
+    pub fn borrow(&self) -> ReadGuide<'_, T> {
+        self.channel
+            .value
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Like `borrow`, but `changed` only returns for values newer than this one.
+    pub fn borrow_and_update(&mut self) -> ReadGuide<'_, T> {
+        let value = self
+            .channel
+            .value
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        // the sender can't change the value, nor the version, while we hold the read lock
+        self.seen = self.channel.version.load(Ordering::Acquire) & !CLOSED;
+        value
+    }
+
+    /// Whether there is a value this receiver hasn't seen, fails once the
+    /// sender is gone.
+    pub fn has_changed(&self) -> Result<bool, RecvError> {
+        let version = self.channel.version.load(Ordering::Acquire);
+        if version & CLOSED != 0 {
+            return Err(RecvError);
+        }
+
+        Ok(version & !CLOSED != self.seen)
+    }
+
+    /// Blocks until there is a value this receiver hasn't seen, and marks it as
+    /// seen. Fails once the sender is gone.
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        loop {
+            let version = self.channel.version.load(Ordering::Acquire);
+            if version & !CLOSED != self.seen {
+                self.seen = version & !CLOSED;
+                return Ok(());
+            }
+            if version & CLOSED != 0 {
+                return Err(RecvError);
+            }
+
+            wait(&self.channel.version, version);
+        }
+    }
+}
+
+// the clone has seen the same version as this receiver
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.version.fetch_or(CLOSED, Ordering::Release);
+        wake_all(&self.channel.version);
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receivers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_watch() {
+    use std::thread;
+
+    let (sender, receiver) = watch(0);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let mut receiver = receiver.clone();
+            s.spawn(move || {
+                // values may be skipped, but never seen twice or going back
+                let mut last = *receiver.borrow_and_update();
+                while receiver.changed().is_ok() {
+                    let v = *receiver.borrow_and_update();
+                    assert!(v > last);
+                    last = v;
+                }
+                // the final value is still there after the sender is gone
+                assert_eq!(*receiver.borrow(), 1000);
+            });
+        }
+
+        for i in 1..=1000 {
+            sender.send(i).unwrap();
+        }
+        drop(sender);
+    });
+}
+
+#[test]
+fn test_watch_versions() {
+    let (sender, mut receiver) = watch(String::from("a"));
+
+    assert_eq!(receiver.has_changed(), Ok(false));
+    assert_eq!(sender.send_replace(String::from("b")), "a");
+    assert_eq!(receiver.has_changed(), Ok(true));
+    // borrow doesn't mark the value as seen
+    assert_eq!(*receiver.borrow(), "b");
+    assert_eq!(receiver.has_changed(), Ok(true));
+    assert_eq!(*receiver.borrow_and_update(), "b");
+    assert_eq!(receiver.has_changed(), Ok(false));
+
+    sender.send_modify(|s| s.push('c'));
+    assert_eq!(receiver.changed(), Ok(()));
+    assert_eq!(*receiver.borrow(), "bc");
+
+    let late = sender.subscribe();
+    assert_eq!(late.has_changed(), Ok(false));
+
+    drop(receiver);
+    drop(late);
+    assert!(sender.send(String::from("d")).is_err());
+    assert_eq!(*sender.borrow(), "bc");
+}
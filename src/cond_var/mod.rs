@@ -1,11 +1,49 @@
-use crate::mutex::{Mutex, MutexGuard};
-use atomic_wait::{wait, wake_all, wake_one};
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use crate::futex::{self, Wake};
+use crate::mutex::Mutex;
+use atomic_wait::{wake_all, wake_one};
+use std::ops::DerefMut;
+use std::ptr;
+use std::sync::{
+    atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+    LockResult, PoisonError,
+};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// A lock guard `CondVar` can wait with: it unlocks by dropping the guard and
+/// takes a new one from the lock after waking up.
+pub trait RelockGuard<'a>: Sized {
+    /// The lock the guard belongs to.
+    type Lock: 'a;
+
+    fn lock(&self) -> &'a Self::Lock;
+
+    /// Locks `lock` again after a wait, the same way the guard was taken.
+    /// `slept` is whether the last wait really slept, rather than found the
+    /// condvar notified already. Only then `notify_all` may have moved the
+    /// thread to `futex`, and the lock may have woken it up there.
+    fn relock(lock: &'a Self::Lock, slept: bool) -> LockResult<Self>;
+
+    /// The futex word threads waiting for `lock` sleep on. If there is one,
+    /// `notify_all` moves the waiters there and `relock` must keep the lock
+    /// marked as contended, so unlocking wakes them up.
+    fn futex(_lock: &'a Self::Lock) -> Option<&'a AtomicU32> {
+        None
+    }
+
+    /// Only checked in debug builds, for locks that know their owner.
+    fn is_held_by_current_thread(&self) -> bool {
+        true
+    }
+}
 
 pub struct CondVar {
     counter: AtomicU32,
     num_waiters: AtomicUsize,
+    // the lock the condvar is bound to
+    lock: AtomicPtr<()>,
+    // the futex of that lock if it has one, notify_all moves the waiters to sleep on it
+    requeue_to: AtomicPtr<AtomicU32>,
 }
 
 #[allow(dead_code)]
@@ -15,46 +53,168 @@ impl CondVar {
             counter: AtomicU32::new(0),
             // 使用 num_waiters 来避免没有必要的
             num_waiters: AtomicUsize::new(0),
+            lock: AtomicPtr::new(ptr::null_mut()),
+            requeue_to: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Unlocks the lock and blocks until notified, then locks it again.
+    pub fn wait<'a, G: RelockGuard<'a>>(&self, guard: G) -> LockResult<G> {
+        match self.wait_until(guard, None) {
+            Ok((guard, _)) => Ok(guard),
+            Err(e) => Err(PoisonError::new(e.into_inner().0)),
+        }
+    }
+
+    /// Waits for as long as `condition` returns true for the protected value.
+    pub fn wait_while<'a, G, F>(&self, mut guard: G, mut condition: F) -> LockResult<G>
+    where
+        G: RelockGuard<'a> + DerefMut,
+        F: FnMut(&mut G::Target) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
         }
+
+        Ok(guard)
+    }
+
+    /// Like `wait`, but gives up after `timeout`. The lock is locked again either way.
+    pub fn wait_timeout<'a, G: RelockGuard<'a>>(
+        &self,
+        guard: G,
+        timeout: Duration,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
+        // too far in the future to ever time out
+        let deadline = Instant::now().checked_add(timeout);
+        self.wait_until(guard, deadline)
     }
 
-    fn wait<'a, T>(&self, v: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    /// Like `wait_while`, but gives up after `timeout`. `timed_out()` is only
+    /// true if the condition still held when the time ran out.
+    pub fn wait_timeout_while<'a, G, F>(
+        &self,
+        mut guard: G,
+        timeout: Duration,
+        mut condition: F,
+    ) -> LockResult<(G, WaitTimeoutResult)>
+    where
+        G: RelockGuard<'a> + DerefMut,
+        F: FnMut(&mut G::Target) -> bool,
+    {
+        let deadline = Instant::now().checked_add(timeout);
+
+        while condition(&mut *guard) {
+            let timed_out;
+            (guard, timed_out) = self.wait_until(guard, deadline)?;
+            if timed_out.timed_out() {
+                let timed_out = WaitTimeoutResult(condition(&mut *guard));
+                return Ok((guard, timed_out));
+            }
+        }
+
+        Ok((guard, WaitTimeoutResult(false)))
+    }
+
+    fn wait_until<'a, G: RelockGuard<'a>>(
+        &self,
+        guard: G,
+        deadline: Option<Instant>,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
+        let lock = guard.lock();
+        #[cfg(debug_assertions)]
+        assert!(
+            guard.is_held_by_current_thread(),
+            "CondVar::wait called with a guard the current thread doesn't hold"
+        );
+        self.bind(lock, G::futex(lock));
         self.num_waiters.fetch_add(1, Ordering::Release);
         let counter = self.counter.load(Ordering::Relaxed);
-        let mutex = v.mutex;
 
-        drop(v);
+        drop(guard);
 
-        // TODO: avoid spurious wake.
-        loop {
-            wait(&self.counter, counter);
-            // self implemention to avoid spurious wake.
-            if self.counter.load(Ordering::Relaxed) != counter {
-                break;
+        // self implemention to avoid spurious wake.
+        let mut slept = false;
+        while self.counter.load(Ordering::Relaxed) == counter {
+            // a waiter moved to the lock times out there, but it was notified already
+            match futex::sleep_until(&self.counter, counter, deadline) {
+                Wake::Woken => slept = true,
+                Wake::Changed => slept = false,
+                Wake::TimedOut => {
+                    slept = false;
+                    break;
+                }
             }
         }
+        let timed_out = self.counter.load(Ordering::Relaxed) == counter;
 
         self.num_waiters.fetch_sub(1, Ordering::Release);
-        mutex.lock()
+        match G::relock(lock, slept) {
+            Ok(guard) => Ok((guard, WaitTimeoutResult(timed_out))),
+            Err(e) => Err(PoisonError::new((
+                e.into_inner(),
+                WaitTimeoutResult(timed_out),
+            ))),
+        }
+    }
+
+    // the first lock used with the condvar is the only one it works with
+    fn bind<L>(&self, lock: &L, futex: Option<&AtomicU32>) {
+        let lock = lock as *const L as *mut ();
+        match self.lock.compare_exchange(
+            ptr::null_mut(),
+            lock,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => {
+                if let Some(futex) = futex {
+                    let futex = futex as *const AtomicU32 as *mut AtomicU32;
+                    self.requeue_to.store(futex, Ordering::Relaxed);
+                }
+            }
+            Err(bound) => assert!(
+                bound == lock,
+                "attempted to use a condition variable with two mutexes"
+            ),
+        }
     }
 
-    #[allow(dead_code)]
-    fn notify_one(&self) {
+    pub fn notify_one(&self) {
         if self.num_waiters.load(Ordering::Acquire) > 0 {
             self.counter.fetch_add(1, Ordering::Relaxed);
             wake_one(&self.counter);
         }
     }
 
-    #[allow(dead_code)]
-    fn notify_all(&self) {
+    /// Wakes up one waiter and moves the others to sleep on the lock, instead
+    /// of waking them all up just to fight over it. Locks without a futex of
+    /// their own get all waiters woken up.
+    pub fn notify_all(&self) {
         if self.num_waiters.load(Ordering::Acquire) > 0 {
-            self.counter.fetch_add(1, Ordering::Relaxed);
-            wake_all(&self.counter);
+            let counter = self.counter.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            // the lock may be gone by now, but then nobody sleeps on the counter
+            // anymore and there is nothing to move.
+            let requeue_to = self.requeue_to.load(Ordering::Relaxed);
+            if requeue_to.is_null() || !futex::requeue(&self.counter, counter, requeue_to) {
+                // the counter changed again, let the waiters sort it out
+                wake_all(&self.counter);
+            }
         }
     }
 }
 
+/// Tells whether a timed wait on a `CondVar` returned because the time ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+#[allow(dead_code)]
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
 #[test]
 fn test_condvar() {
     let m = Mutex::new(0);
@@ -63,16 +223,172 @@ fn test_condvar() {
     thread::scope(|s| {
         s.spawn(|| {
             for _ in 0..1000 {
-                *m.lock() += 1;
+                *m.lock().unwrap() += 1;
                 cond_v.notify_one();
             }
         });
 
-        let mut m_guide = m.lock();
+        let mut m_guide = m.lock().unwrap();
         while *m_guide != 1000 {
-            m_guide = cond_v.wait(m_guide);
+            m_guide = cond_v.wait(m_guide).unwrap();
+        }
+    });
+
+    assert_eq!(1000, *m.lock().unwrap());
+}
+
+#[test]
+fn test_wait_while() {
+    let m = Mutex::new(0);
+    let cond_v = CondVar::new();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..1000 {
+                *m.lock().unwrap() += 1;
+                cond_v.notify_all();
+            }
+        });
+
+        let m_guide = cond_v
+            .wait_while(m.lock().unwrap(), |n| *n != 1000)
+            .unwrap();
+        assert_eq!(*m_guide, 1000);
+    });
+}
+
+#[test]
+fn test_wait_timeout() {
+    let m = Mutex::new(0);
+    let cond_v = CondVar::new();
+
+    // nobody notifies, so the wait has to time out
+    let start = Instant::now();
+    let (m_guide, r) = cond_v
+        .wait_timeout(m.lock().unwrap(), Duration::from_millis(20))
+        .unwrap();
+    assert!(r.timed_out());
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    drop(m_guide);
+
+    let (m_guide, r) = cond_v
+        .wait_timeout_while(m.lock().unwrap(), Duration::from_millis(20), |n| *n == 0)
+        .unwrap();
+    assert!(r.timed_out());
+    drop(m_guide);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(10));
+            *m.lock().unwrap() = 1;
+            cond_v.notify_one();
+        });
+
+        let (m_guide, r) = cond_v
+            .wait_timeout_while(m.lock().unwrap(), Duration::from_secs(10), |n| *n == 0)
+            .unwrap();
+        assert!(!r.timed_out());
+        assert_eq!(*m_guide, 1);
+    });
+}
+
+#[test]
+fn test_notify_all_requeue() {
+    let m = Mutex::new(0);
+    let cond_v = CondVar::new();
+
+    // every round, all waiters have to see the new generation before the next one
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                for generation in 1..=100 {
+                    let mut m_guide = cond_v
+                        .wait_while(m.lock().unwrap(), |n| *n / 8 < generation)
+                        .unwrap();
+                    *m_guide += 1;
+                    cond_v.notify_all();
+                }
+            });
+        }
+
+        let mut m_guide = m.lock().unwrap();
+        *m_guide = 8;
+        cond_v.notify_all();
+        drop(m_guide);
+    });
+
+    assert_eq!(*m.lock().unwrap(), 8 * 101);
+}
+
+#[test]
+#[should_panic(expected = "attempted to use a condition variable with two mutexes")]
+fn test_two_mutexes() {
+    let m1 = Mutex::new(0);
+    let m2 = Mutex::new(0);
+    let cond_v = CondVar::new();
+
+    drop(cond_v.wait_timeout(m1.lock().unwrap(), Duration::from_millis(1)));
+    drop(cond_v.wait_timeout(m1.lock().unwrap(), Duration::from_millis(1)));
+    drop(cond_v.wait_timeout(m2.lock().unwrap(), Duration::from_millis(1)));
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_wait_guard_from_other_thread() {
+    let m = Mutex::new(0);
+    let cond_v = CondVar::new();
+
+    let m_guide = m.lock().unwrap();
+    let r = thread::scope(|s| {
+        s.spawn(|| drop(cond_v.wait_timeout(m_guide, Duration::from_millis(1))))
+            .join()
+    });
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_other_locks() {
+    use crate::{rwlock::RwLock, spin_lock::SpinLock};
+
+    // readers wait for the version to change, the writer bumps it
+    let rwlock = RwLock::new(0);
+    let cond_v = CondVar::new();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut version = rwlock.read().unwrap();
+                while *version == 0 {
+                    version = cond_v.wait(version).unwrap();
+                }
+                assert_eq!(*version, 1);
+            });
         }
+
+        thread::sleep(Duration::from_millis(10));
+        *rwlock.write().unwrap() = 1;
+        cond_v.notify_all();
     });
 
-    assert_eq!(1000, *m.lock());
+    let cond_v = CondVar::new();
+    let w = cond_v
+        .wait_timeout_while(rwlock.write().unwrap(), Duration::from_millis(1), |n| {
+            *n == 1
+        })
+        .unwrap();
+    assert!(w.1.timed_out());
+    drop(w);
+
+    let spin_lock = SpinLock::new(0);
+    let cond_v = CondVar::new();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..100 {
+                *spin_lock.lock() += 1;
+                cond_v.notify_one();
+            }
+        });
+
+        let n = cond_v.wait_while(spin_lock.lock(), |n| *n != 100).unwrap();
+        assert_eq!(*n, 100);
+    });
 }
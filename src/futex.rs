@@ -0,0 +1,176 @@
+use std::{
+    sync::atomic::AtomicU32,
+    time::{Duration, Instant},
+};
+
+/// How `sleep_until` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wake {
+    /// The thread slept and was woken up, or woke up spuriously.
+    Woken,
+    /// `a` didn't hold `expected`, the thread didn't sleep at all.
+    Changed,
+    /// The deadline has passed.
+    TimedOut,
+}
+
+/// Blocks while `a` holds `expected`, until woken up or `deadline` has passed.
+/// Without a deadline this is the same as `atomic_wait::wait`.
+///
+/// Returns `false` if the deadline has passed. Like `wait`, it can return
+/// spuriously, so callers must check the value again.
+pub fn wait_until(a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> bool {
+    sleep_until(a, expected, deadline) != Wake::TimedOut
+}
+
+/// Like `wait_until`, but tells whether the thread really went to sleep, for
+/// callers that treat the threads a wake up is meant for differently.
+pub fn sleep_until(a: &AtomicU32, expected: u32, deadline: Option<Instant>) -> Wake {
+    let timeout = match deadline {
+        None => None,
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Wake::TimedOut;
+            }
+            Some(deadline - now)
+        }
+    };
+
+    sleep(a, expected, timeout)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sleep(a: &AtomicU32, expected: u32, timeout: Option<Duration>) -> Wake {
+    // FUTEX_WAIT takes a relative timeout, a too long one is just clamped.
+    let ts = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            ts.as_ref()
+                .map_or(std::ptr::null(), |ts| ts as *const libc::timespec),
+        )
+    };
+    if r >= 0 {
+        return Wake::Woken;
+    }
+
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::ETIMEDOUT) => Wake::TimedOut,
+        Some(libc::EAGAIN) => Wake::Changed,
+        // EINTR, a signal counts as a spurious wake up
+        _ => Wake::Woken,
+    }
+}
+
+// there is no portable timed futex wait, so poll the value until the deadline.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn sleep(a: &AtomicU32, expected: u32, timeout: Option<Duration>) -> Wake {
+    use std::sync::atomic::Ordering;
+
+    // a change between this load and going to sleep counts as a wake up
+    if a.load(Ordering::Relaxed) != expected {
+        return Wake::Changed;
+    }
+
+    let Some(timeout) = timeout else {
+        atomic_wait::wait(a, expected);
+        return Wake::Woken;
+    };
+
+    let deadline = Instant::now() + timeout;
+    while a.load(Ordering::Relaxed) == expected {
+        let now = Instant::now();
+        if now >= deadline {
+            return Wake::TimedOut;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_micros(100)));
+    }
+
+    Wake::Woken
+}
+
+/// Wakes up one thread waiting on `a`, returns whether a sleeping thread was
+/// actually woken up. Where that can't be known, it returns `false`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn wake_one(a: &AtomicU32) -> bool {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+        )
+    };
+
+    r > 0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn wake_one(a: &AtomicU32) -> bool {
+    atomic_wait::wake_one(a);
+    false
+}
+
+/// Wakes up one thread waiting on `from` and moves all others to wait on `to`,
+/// if `from` still holds `expected`. Returns `false` if it didn't, then nobody
+/// was woken up or moved.
+///
+/// `to` is only handed to the kernel, which doesn't read it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn requeue(from: &AtomicU32, expected: u32, to: *const AtomicU32) -> bool {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            from as *const AtomicU32,
+            libc::FUTEX_CMP_REQUEUE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+            // the number of threads to move goes where FUTEX_WAIT takes the timeout
+            i32::MAX as libc::c_long,
+            to,
+            expected,
+        )
+    };
+
+    r >= 0
+}
+
+// moving threads needs the kernel's help, so wake them all up instead.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn requeue(from: &AtomicU32, _expected: u32, _to: *const AtomicU32) -> bool {
+    atomic_wait::wake_all(from);
+    true
+}
+
+#[test]
+fn test_sleep_until() {
+    use std::{sync::atomic::Ordering, thread};
+
+    let a = AtomicU32::new(0);
+    assert_eq!(sleep_until(&a, 1, None), Wake::Changed);
+    assert_eq!(
+        sleep_until(&a, 0, Some(Instant::now() + Duration::from_millis(1))),
+        Wake::TimedOut
+    );
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(20));
+            a.store(1, Ordering::Relaxed);
+            wake_one(&a);
+        });
+        // it only doesn't sleep if the store came first
+        while a.load(Ordering::Relaxed) == 0 {
+            if sleep_until(&a, 0, None) == Wake::Changed {
+                assert_eq!(a.load(Ordering::Relaxed), 1);
+            }
+        }
+    });
+}
@@ -1,4 +1,5 @@
 #![feature(arc_into_inner)]
+#![feature(coerce_unsized, unsize, set_ptr_value, allocator_api)]
 use std::{
     hint::black_box,
     sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering},
@@ -12,11 +13,13 @@ mod carton;
 mod channel;
 mod cond_var;
 mod condition_var;
+mod futex;
 mod interior_mutability;
 mod mutex;
 mod mutex_usage;
 pub mod ordering;
 mod parking;
+mod rc;
 mod reference_counting;
 mod rwlock;
 mod scoped_thread;
@@ -1,29 +1,62 @@
-use atomic_wait::{wait, wake_all, wake_one};
+use crate::cond_var::RelockGuard;
+use crate::futex::{self, Wake};
+use atomic_wait::wake_one;
 use std::{
     cell::UnsafeCell,
-    hint::spin_loop,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicI16, AtomicU16, AtomicU32, Ordering},
+    panic::{RefUnwindSafe, UnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicI16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        LockResult, OnceLock, PoisonError, TryLockError, TryLockResult,
+    },
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+mod policy;
+
+#[allow(unused_imports)]
+pub use policy::{AdaptiveSpin, FutexOnly, LockPolicy, SpinThenPark};
+
 const UNLOCKED: u32 = 0;
 const LOCKED: u32 = 1;
 const LOCKED_WITH_WAITERS: u32 = 2;
+// the lock is still held but given to a thread that already waited, new
+// comers must not take it.
+const HANDED_OFF: u32 = 3;
 
-pub struct Mutex<T> {
+// after the lock has been passed around unfairly for this long, the next
+// unlock hands it to a waiter directly, like parking_lot's eventual fairness.
+const FAIR_TIMEOUT: Duration = Duration::from_micros(500);
+
+pub struct Mutex<T, P: LockPolicy = SpinThenPark> {
     state: AtomicU32,
+    /// Set when a thread panics while holding the lock, the value may be in a broken state.
+    poisoned: AtomicBool,
+    // nanos since `epoch()` after which the next contended unlock is fair
+    fair_timeout: AtomicU64,
+    policy: P::State,
+    // the thread holding the lock, only tracked to catch misuse in debug builds
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
     value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
+unsafe impl<T, P: LockPolicy> Sync for Mutex<T, P> where T: Send {}
+
+// a panic can't leave the value broken unnoticed, the lock will be poisoned.
+impl<T, P: LockPolicy> UnwindSafe for Mutex<T, P> {}
+impl<T, P: LockPolicy> RefUnwindSafe for Mutex<T, P> {}
 
-pub struct MutexGuard<'a, T> {
-    pub mutex: &'a Mutex<T>,
+pub struct MutexGuard<'a, T, P: LockPolicy = SpinThenPark> {
+    pub mutex: &'a Mutex<T, P>,
+    // whether the thread was already panicking when the lock was taken,
+    // so that unwinding through an unrelated panic doesn't poison the lock.
+    panicking: bool,
 }
 
-impl<'a, T> Deref for MutexGuard<'a, T> {
+impl<'a, T, P: LockPolicy> Deref for MutexGuard<'a, T, P> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -31,90 +64,331 @@ impl<'a, T> Deref for MutexGuard<'a, T> {
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T, P: LockPolicy> DerefMut for MutexGuard<'_, T, P> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.mutex.value.get() }
     }
 }
 
-#[allow(dead_code)]
-fn lock_contended_v1(state: &AtomicU32) {
-    loop {
-        wait(state, LOCKED);
-        if state
-            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
-        {
-            break;
-        }
-    }
+// an address unique to each running thread
+#[cfg(debug_assertions)]
+fn current_thread() -> usize {
+    thread_local!(static ID: u8 = const { 0 });
+    ID.with(|id| id as *const u8 as usize)
 }
 
-#[allow(dead_code)]
-fn lock_contended_v2(state: &AtomicU32) {
-    // still locked, the thread need to wait
-    while state.swap(LOCKED_WITH_WAITERS, Ordering::Release) != UNLOCKED {
-        wait(state, LOCKED_WITH_WAITERS);
-    }
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
 }
 
-#[allow(dead_code)]
-fn lock_contended(state: &AtomicU32) {
-    let mut spin_count = 0;
+impl<T> Mutex<T> {
+    #[allow(dead_code)]
+    pub fn new(v: T) -> Self {
+        Self::with_policy(v, SpinThenPark)
+    }
+}
 
-    while state.load(Ordering::Relaxed) == LOCKED && spin_count < 100 {
-        spin_loop();
-        spin_count += 1;
+impl<T, P: LockPolicy> Mutex<T, P> {
+    // the policy is only a marker, e.g. `Mutex::with_policy(0, FutexOnly)`
+    #[allow(dead_code)]
+    pub fn with_policy(v: T, _policy: P) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            poisoned: AtomicBool::new(false),
+            fair_timeout: AtomicU64::new(0),
+            policy: P::State::default(),
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+            value: UnsafeCell::new(v),
+        }
     }
 
-    if state
-        .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
-        .is_ok()
-    {
-        return;
+    #[allow(dead_code)]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T, P>> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended(None);
+        }
+
+        self.guard()
     }
 
-    // still locked, the thread need to wait
-    while state.swap(LOCKED_WITH_WAITERS, Ordering::Release) != 0 {
-        wait(state, LOCKED_WITH_WAITERS);
+    #[allow(dead_code)]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T, P>> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(TryLockError::WouldBlock);
+        }
+
+        Ok(self.guard()?)
     }
-}
 
-impl<T> Mutex<T> {
     #[allow(dead_code)]
-    pub fn new(v: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            value: UnsafeCell::new(v),
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<MutexGuard<'_, T, P>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            // too far in the future to ever time out
+            None => Ok(self.lock()?),
         }
     }
 
     #[allow(dead_code)]
-    pub fn lock(&self) -> MutexGuard<'_, T> {
+    pub fn try_lock_until(&self, deadline: Instant) -> TryLockResult<MutexGuard<'_, T, P>> {
         if self
             .state
             .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
             .is_err()
+            && !self.lock_contended(Some(deadline))
         {
-            lock_contended_v1(&self.state);
-            // lock_contended_v2(&self.state);
-            // lock_contended(&self.state);
+            return Err(TryLockError::WouldBlock);
         }
 
-        MutexGuard { mutex: self }
+        Ok(self.guard()?)
     }
-}
 
-impl<T> Drop for MutexGuard<'_, T> {
-    fn drop(&mut self) {
+    // returns false if the deadline passed before the lock could be taken.
+    fn lock_contended(&self, deadline: Option<Instant>) -> bool {
+        P::spin(&self.state, &self.policy) || self.park(deadline, false)
+    }
+
+    // a waiter that gives up leaves the state as LOCKED_WITH_WAITERS, the worst case
+    // is an unnecessary wake up when the lock is released.
+    //
+    // only a thread that was woken up from sleeping on the state (`woken`) may
+    // take a handed off lock. Not a newcomer, nor the thread that just unlocked,
+    // and not one whose wait failed because the state had changed to HANDED_OFF,
+    // the thread `unlock_fair` woke would go back to sleep then.
+    fn park(&self, deadline: Option<Instant>, mut woken: bool) -> bool {
+        loop {
+            let expected = match self.state.load(Ordering::Relaxed) {
+                HANDED_OFF if woken => {
+                    if self
+                        .state
+                        .compare_exchange(
+                            HANDED_OFF,
+                            LOCKED_WITH_WAITERS,
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        return true;
+                    }
+                    continue;
+                }
+                HANDED_OFF => HANDED_OFF,
+                // we don't know whether others are still waiting, so keep the waiters mark
+                UNLOCKED => {
+                    if self
+                        .state
+                        .compare_exchange(
+                            UNLOCKED,
+                            LOCKED_WITH_WAITERS,
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        return true;
+                    }
+                    continue;
+                }
+                LOCKED => {
+                    if self
+                        .state
+                        .compare_exchange(
+                            LOCKED,
+                            LOCKED_WITH_WAITERS,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        )
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    LOCKED_WITH_WAITERS
+                }
+                s => s,
+            };
+
+            // still locked, the thread need to wait
+            woken = match futex::sleep_until(&self.state, expected, deadline) {
+                Wake::Woken => true,
+                Wake::Changed => false,
+                Wake::TimedOut => return false,
+            };
+        }
+    }
+
+    fn unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+
+        if self.state.load(Ordering::Relaxed) == LOCKED_WITH_WAITERS {
+            let now = epoch().elapsed().as_nanos() as u64;
+            if now >= self.fair_timeout.load(Ordering::Relaxed) {
+                self.fair_timeout
+                    .store(now + FAIR_TIMEOUT.as_nanos() as u64, Ordering::Relaxed);
+                self.unlock_fair();
+                return;
+            }
+        }
+
         // sometimes we don't need to wake thread, so we have to make sure there is thread to wake up
-        // if self.mutex.state.swap(UNLOCKED, Ordering::Release) == LOCKED_WITH_WAITERS {
-        //     wake_one(&self.mutex.state);
-        // }
+        if self.state.swap(UNLOCKED, Ordering::Release) == LOCKED_WITH_WAITERS {
+            wake_one(&self.state);
+        }
+    }
+
+    fn unlock_fair(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+
+        if self
+            .state
+            .compare_exchange(LOCKED, UNLOCKED, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+
+        // keep the lock locked and give it to a sleeping waiter
+        self.state.store(HANDED_OFF, Ordering::Release);
+        if futex::wake_one(&self.state) {
+            return;
+        }
+
+        // nobody was asleep, the waiters may have timed out. take the lock back and
+        // release it normally, unless a waiter that was about to sleep already took it.
+        if self
+            .state
+            .compare_exchange(HANDED_OFF, UNLOCKED, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            // threads that started to wait on HANDED_OFF in the meantime
+            wake_one(&self.state);
+        }
+    }
+
+    // locks like a thread that slept on `state`, so the lock stays marked as having
+    // waiters. `CondVar` relocks this way, because it may have moved other waiters
+    // to sleep on `state`, and only an unlock with waiters wakes them up.
+    //
+    // `slept` is whether the thread was woken up from sleeping, maybe on `state`
+    // after `CondVar` moved it there, only then it may take a handed off lock.
+    fn lock_with_waiters(&self, slept: bool) -> LockResult<MutexGuard<'_, T, P>> {
+        self.park(None, slept);
+        self.guard()
+    }
 
-        // for v1
-        self.mutex.state.store(UNLOCKED, Ordering::Release);
-        wake_one(&self.mutex.state);
+    // whether the calling thread holds the lock, guards can be sent to other threads
+    #[cfg(debug_assertions)]
+    fn is_held_by_current_thread(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_thread()
+    }
+
+    fn guard(&self) -> LockResult<MutexGuard<'_, T, P>> {
+        #[cfg(debug_assertions)]
+        self.owner.store(current_thread(), Ordering::Relaxed);
+
+        let guard = MutexGuard {
+            mutex: self,
+            panicking: thread::panicking(),
+        };
+
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let value = self.value.into_inner();
+
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    // &mut self guarantees no guard is alive, so there is no need to touch the state.
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        let value = self.value.get_mut();
+
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl<T, P: LockPolicy> MutexGuard<'_, T, P> {
+    /// Unlocks the mutex and hands it to a waiting thread directly, instead of
+    /// letting whoever comes first take it.
+    #[allow(dead_code)]
+    pub fn unlock_fair(guard: Self) {
+        let guard = ManuallyDrop::new(guard);
+        guard.poison();
+        guard.mutex.unlock_fair();
+    }
+
+    fn poison(&self) {
+        if !self.panicking && thread::panicking() {
+            self.mutex.poisoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a, T, P: LockPolicy> RelockGuard<'a> for MutexGuard<'a, T, P> {
+    type Lock = Mutex<T, P>;
+
+    fn lock(&self) -> &'a Mutex<T, P> {
+        self.mutex
+    }
+
+    // `CondVar` may have moved other waiters to sleep on the state
+    fn relock(mutex: &'a Mutex<T, P>, slept: bool) -> LockResult<Self> {
+        mutex.lock_with_waiters(slept)
+    }
+
+    fn futex(mutex: &'a Mutex<T, P>) -> Option<&'a AtomicU32> {
+        Some(&mutex.state)
+    }
+
+    #[cfg(debug_assertions)]
+    fn is_held_by_current_thread(&self) -> bool {
+        self.mutex.is_held_by_current_thread()
+    }
+}
+
+impl<T, P: LockPolicy> Drop for MutexGuard<'_, T, P> {
+    fn drop(&mut self) {
+        self.poison();
+        self.mutex.unlock();
     }
 }
 
@@ -124,10 +398,10 @@ fn test_uncondended() {
     std::hint::black_box(&m);
     let start = Instant::now();
     for _ in 0..5_000_000 {
-        *m.lock() += 1;
+        *m.lock().unwrap() += 1;
     }
     let duration = start.elapsed();
-    println!("locked {} times in {:?}", *m.lock(), duration);
+    println!("locked {} times in {:?}", *m.lock().unwrap(), duration);
 }
 
 #[test]
@@ -139,13 +413,228 @@ fn test_condeneded() {
         for _ in 0..10 {
             s.spawn(|| {
                 for _ in 0..5_000_000 {
-                    *m.lock() += 1;
+                    *m.lock().unwrap() += 1;
                 }
             });
         }
     });
     let duration = start.elapsed();
-    println!("locked {} times in {:?}", *m.lock(), duration);
+    println!("locked {} times in {:?}", *m.lock().unwrap(), duration);
+}
+
+#[test]
+fn test_policies() {
+    fn count<P: LockPolicy>(m: Mutex<usize, P>) {
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..100_000 {
+                        *m.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
+        assert_eq!(m.into_inner().unwrap(), 800_000);
+    }
+
+    count(Mutex::with_policy(0, FutexOnly));
+    count(Mutex::with_policy(0, SpinThenPark::<10>));
+    count(Mutex::with_policy(0, AdaptiveSpin));
+}
+
+#[test]
+fn test_unlock_fair_contended() {
+    let m = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                for i in 0..20_000 {
+                    let mut g = m.lock().unwrap();
+                    *g += 1;
+                    if i % 2 == 0 {
+                        MutexGuard::unlock_fair(g);
+                    }
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..20_000 {
+                    if let Ok(mut g) = m.try_lock_for(Duration::from_micros(5)) {
+                        *g += 1;
+                    }
+                }
+            });
+        }
+    });
+
+    assert!(*m.lock().unwrap() >= 160_000);
+    assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
+}
+
+#[test]
+fn test_unlock_fair() {
+    let m = Mutex::new(0);
+    let g = m.lock().unwrap();
+    let release = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let waiter = s.spawn(|| {
+            let mut g = m.lock().unwrap();
+            *g += 1;
+            // keep the lock until the main thread tried to take it
+            while !release.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+        });
+
+        // let the waiter fall asleep
+        while m.state.load(Ordering::Relaxed) != LOCKED_WITH_WAITERS {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        MutexGuard::unlock_fair(g);
+        // the lock belongs to the waiter now, we can't barge in
+        assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+        release.store(true, Ordering::Relaxed);
+        waiter.join().unwrap();
+    });
+
+    assert_eq!(*m.lock().unwrap(), 1);
+    assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
+}
+
+#[test]
+fn test_eventual_fairness() {
+    // far more rounds than FAIR_TIMEOUT needs, so the holder stops even if it never hands off
+    const ROUNDS: usize = 10_000;
+
+    let m = Mutex::new(0);
+    let acquired = AtomicBool::new(false);
+    let rounds = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        // keeps the lock almost all the time
+        s.spawn(|| {
+            while !acquired.load(Ordering::Relaxed) && rounds.load(Ordering::Relaxed) < ROUNDS {
+                let _g = m.lock().unwrap();
+                let busy = Instant::now();
+                while busy.elapsed() < Duration::from_micros(50) {}
+                rounds.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        thread::sleep(Duration::from_millis(10));
+        *m.lock().unwrap() += 1;
+        // the holder must not have given up yet, or we got the lock for free
+        assert!(rounds.load(Ordering::Relaxed) < ROUNDS);
+        acquired.store(true, Ordering::Relaxed);
+    });
+
+    assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
+}
+
+#[test]
+fn test_poison() {
+    let m = Mutex::new(0);
+
+    thread::scope(|s| {
+        let r = s
+            .spawn(|| {
+                let mut g = m.lock().unwrap();
+                *g += 1;
+                panic!("panic while holding the lock");
+            })
+            .join();
+        assert!(r.is_err());
+    });
+
+    assert!(m.is_poisoned());
+    assert!(m.lock().is_err());
+    let g = m.lock().unwrap_or_else(PoisonError::into_inner);
+    assert_eq!(*g, 1);
+    drop(g);
+
+    m.clear_poison();
+    assert!(!m.is_poisoned());
+    *m.lock().unwrap() += 1;
+
+    // a panic that started before the lock was taken doesn't poison it
+    let r = std::panic::catch_unwind(|| {
+        struct LockOnDrop<'a>(&'a Mutex<i32>);
+        impl Drop for LockOnDrop<'_> {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let _l = LockOnDrop(&m);
+        panic!("unrelated panic");
+    });
+    assert!(r.is_err());
+    assert!(!m.is_poisoned());
+    assert_eq!(m.into_inner().unwrap(), 3);
+}
+
+#[test]
+fn test_try_lock() {
+    let m = Mutex::new(0);
+
+    let g = m.try_lock().unwrap();
+    assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let start = Instant::now();
+            let r = m.try_lock_for(Duration::from_millis(50));
+            assert!(matches!(r, Err(TryLockError::WouldBlock)));
+            // only a lower bound, a timed out wait never returns before its deadline
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        });
+    });
+
+    // the timed out waiter must not leave the lock in a state where the next waiter is never woken
+    thread::scope(|s| {
+        s.spawn(|| {
+            *m.try_lock_for(Duration::from_secs(10)).unwrap() += 1;
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(g);
+    });
+
+    assert_eq!(*m.try_lock().unwrap(), 1);
+    assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
+}
+
+#[test]
+fn test_lock_timeout_contended() {
+    let m = Mutex::new(0);
+    let timeouts = std::sync::atomic::AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    *m.lock().unwrap() += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    match m.try_lock_for(Duration::from_micros(10)) {
+                        Ok(mut g) => *g += 1,
+                        Err(_) => {
+                            timeouts.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let timeouts = timeouts.load(Ordering::Relaxed);
+    assert_eq!(*m.lock().unwrap(), 80_000 - timeouts);
+    assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
 }
 
 // 原子值操作溢出之后会从最小值开始计数
@@ -0,0 +1,93 @@
+use super::{LOCKED, LOCKED_WITH_WAITERS, UNLOCKED};
+use std::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Decides what a thread does when it finds the mutex already locked.
+///
+/// All policies share the mutex state machine, the only difference is how
+/// long a thread spins before it goes to sleep.
+pub trait LockPolicy {
+    /// Extra per mutex state the policy needs, stored inside the `Mutex`.
+    type State: Default + Send + Sync;
+
+    /// Called after the fast path failed, returns true if the lock was taken
+    /// while spinning. Otherwise the thread parks.
+    fn spin(state: &AtomicU32, policy: &Self::State) -> bool;
+}
+
+/// Goes to sleep right away, never burns cpu on a held lock.
+#[allow(dead_code)]
+pub struct FutexOnly;
+
+/// Spins up to `SPINS` times while the lock is held without waiters, then sleeps.
+pub struct SpinThenPark<const SPINS: u32 = 100>;
+
+/// Spins like `SpinThenPark`, but adjusts the number of spins to how long it
+/// took to get the lock in the past, like glibc's adaptive mutex.
+#[allow(dead_code)]
+pub struct AdaptiveSpin;
+
+#[allow(dead_code)]
+const MAX_ADAPTIVE_SPINS: u32 = 1000;
+
+impl LockPolicy for FutexOnly {
+    type State = ();
+
+    fn spin(_: &AtomicU32, _: &()) -> bool {
+        false
+    }
+}
+
+impl<const SPINS: u32> LockPolicy for SpinThenPark<SPINS> {
+    type State = ();
+
+    fn spin(state: &AtomicU32, _: &()) -> bool {
+        let mut spin_count = 0;
+
+        // there is no point to spin if others are already waiting
+        while state.load(Ordering::Relaxed) == LOCKED && spin_count < SPINS {
+            spin_loop();
+            spin_count += 1;
+        }
+
+        state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl LockPolicy for AdaptiveSpin {
+    // the moving average of spins needed to get the lock
+    type State = AtomicU32;
+
+    fn spin(state: &AtomicU32, spins: &AtomicU32) -> bool {
+        let average = spins.load(Ordering::Relaxed);
+        let max_spins = (average * 2 + 10).min(MAX_ADAPTIVE_SPINS);
+        let mut spin_count = 0;
+
+        let acquired = loop {
+            if state
+                .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break true;
+            }
+
+            if spin_count >= max_spins || state.load(Ordering::Relaxed) >= LOCKED_WITH_WAITERS {
+                break false;
+            }
+
+            spin_loop();
+            spin_count += 1;
+        };
+
+        // move the average an eighth of the way towards this attempt, it's only a hint
+        // so racing updates are fine.
+        let average = average as i64 + (spin_count as i64 - average as i64) / 8;
+        spins.store(average as u32, Ordering::Relaxed);
+
+        acquired
+    }
+}
@@ -0,0 +1,501 @@
+use crate::arc::{move_into_counted, refcount_overflow};
+use std::{
+    alloc::{self, AllocError, Allocator, Global, Layout},
+    any::Any,
+    borrow::Borrow,
+    cell::Cell,
+    cmp::Ordering as CmpOrdering,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::{PhantomData, Unsize},
+    mem::{self, ManuallyDrop, MaybeUninit},
+    ops::{CoerceUnsized, Deref},
+    ptr::{self, NonNull},
+};
+
+// The same two counts as `arc::ArcData`, but only one thread ever sees them, so
+// `get_mut` doesn't need to lock anything and plain loads and stores will do.
+#[repr(C)]
+struct RcData<T: ?Sized> {
+    // Number of `Rc`s
+    data_ref_count: Cell<usize>,
+    // Number of `Weak`s, plus one if there are any `Rc`s.
+    alloc_ref_count: Cell<usize>,
+    data: ManuallyDrop<T>,
+}
+
+// Only reachable through `mem::forget`, a count can't go past the number of
+// `Rc`s and `Weak`s that fit in memory otherwise.
+fn increment(count: &Cell<usize>) {
+    let n = count.get();
+    if n == usize::MAX {
+        refcount_overflow();
+    }
+    count.set(n + 1);
+}
+
+/// `arc::Arc` without the atomics, for values that stay on one thread.
+pub struct Rc<T: ?Sized, A: Allocator = Global> {
+    ptr: NonNull<RcData<T>>,
+    alloc: A,
+    // like `send_sync_trait::U`, the counts aren't atomic, so it can neither be
+    // sent to nor shared with another thread
+    _not_send_sync: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized, A: Allocator> CoerceUnsized<Rc<U, A>> for Rc<T, A> {}
+
+impl<T> Rc<T> {
+    #[allow(dead_code)]
+    pub fn new(v: T) -> Self {
+        Self::new_in(v, Global)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_new(v: T) -> Result<Self, AllocError> {
+        Self::try_new_in(v, Global)
+    }
+
+    /// See `arc::Arc::new_cyclic`.
+    #[allow(dead_code)]
+    pub fn new_cyclic(f: impl FnOnce(&Weak<T>) -> T) -> Self {
+        let uninit = Box::leak(Box::new(RcData {
+            data_ref_count: Cell::new(0),
+            alloc_ref_count: Cell::new(1),
+            data: ManuallyDrop::new(MaybeUninit::<T>::uninit()),
+        }));
+        let weak = Weak {
+            ptr: NonNull::from(uninit).cast::<RcData<T>>(),
+            alloc: Global,
+            _not_send_sync: PhantomData,
+        };
+
+        let v = f(&weak);
+
+        unsafe { ptr::addr_of_mut!((*weak.ptr.as_ptr()).data).write(ManuallyDrop::new(v)) };
+        weak.data().data_ref_count.set(1);
+
+        let weak = ManuallyDrop::new(weak);
+        Rc {
+            ptr: weak.ptr,
+            alloc: Global,
+            _not_send_sync: PhantomData,
+        }
+    }
+}
+
+impl<T, A: Allocator> Rc<T, A> {
+    #[allow(dead_code)]
+    pub fn new_in(v: T, alloc: A) -> Self {
+        match Self::try_new_in(v, alloc) {
+            Ok(rc) => rc,
+            Err(AllocError) => alloc::handle_alloc_error(Layout::new::<RcData<T>>()),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_new_in(v: T, alloc: A) -> Result<Self, AllocError> {
+        let data = Box::try_new_in(
+            RcData {
+                data_ref_count: Cell::new(1),
+                alloc_ref_count: Cell::new(1),
+                data: ManuallyDrop::new(v),
+            },
+            alloc,
+        )?;
+        let (ptr, alloc) = Box::into_raw_with_allocator(data);
+        Ok(Rc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            alloc,
+            _not_send_sync: PhantomData,
+        })
+    }
+
+    /// Returns the value if this is the only `Rc`, otherwise gives the `Rc` back.
+    #[allow(dead_code)]
+    pub fn try_unwrap(rc: Self) -> Result<T, Self> {
+        if rc.data().data_ref_count.get() != 1 {
+            return Err(rc);
+        }
+
+        let rc = ManuallyDrop::new(rc);
+        rc.data().data_ref_count.set(0);
+        unsafe {
+            let v = ManuallyDrop::take(&mut (*rc.ptr.as_ptr()).data);
+            drop(Weak {
+                ptr: rc.ptr,
+                alloc: ptr::read(&rc.alloc),
+                _not_send_sync: PhantomData,
+            });
+            Ok(v)
+        }
+    }
+
+    /// Nothing can race to drop the other `Rc`s, so this is just `try_unwrap`.
+    #[allow(dead_code)]
+    pub fn into_inner(rc: Self) -> Option<T> {
+        Self::try_unwrap(rc).ok()
+    }
+
+    /// See `arc::Arc::make_mut`.
+    #[allow(dead_code)]
+    pub fn make_mut(rc: &mut Self) -> &mut T
+    where
+        T: Clone,
+        A: Clone,
+    {
+        if rc.data().data_ref_count.get() != 1 {
+            *rc = Rc::new_in((**rc).clone(), rc.alloc.clone());
+        } else if rc.data().alloc_ref_count.get() != 1 {
+            // the `Weak`s can't upgrade once the value moved out
+            rc.data().data_ref_count.set(0);
+            let v = unsafe { ManuallyDrop::take(&mut (*rc.ptr.as_ptr()).data) };
+            let fresh = Rc::new_in(v, rc.alloc.clone());
+            let old = ManuallyDrop::new(mem::replace(rc, fresh));
+            drop(Weak {
+                ptr: old.ptr,
+                alloc: unsafe { ptr::read(&old.alloc) },
+                _not_send_sync: PhantomData,
+            });
+        }
+
+        unsafe { &mut (*rc.ptr.as_ptr()).data }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Rc<T, A> {
+    fn data(&self) -> &RcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    #[allow(dead_code)]
+    pub fn strong_count(rc: &Self) -> usize {
+        rc.data().data_ref_count.get()
+    }
+
+    #[allow(dead_code)]
+    pub fn weak_count(rc: &Self) -> usize {
+        rc.data().alloc_ref_count.get() - 1
+    }
+
+    #[allow(dead_code)]
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    #[allow(dead_code)]
+    pub fn as_ptr(rc: &Self) -> *const T {
+        unsafe { ptr::addr_of!((*rc.ptr.as_ptr()).data) as *const T }
+    }
+
+    #[allow(dead_code)]
+    pub fn downgrade(rc: &Self) -> Weak<T, A>
+    where
+        A: Clone,
+    {
+        increment(&rc.data().alloc_ref_count);
+        Weak {
+            ptr: rc.ptr,
+            alloc: rc.alloc.clone(),
+            _not_send_sync: PhantomData,
+        }
+    }
+
+    /// Mutable access if there are no other `Rc`s nor `Weak`s.
+    #[allow(dead_code)]
+    pub fn get_mut(rc: &mut Self) -> Option<&mut T> {
+        if rc.data().data_ref_count.get() != 1 || rc.data().alloc_ref_count.get() != 1 {
+            return None;
+        }
+
+        unsafe { Some(&mut (*rc.ptr.as_ptr()).data) }
+    }
+}
+
+impl<T: ?Sized> Rc<T> {
+    #[allow(dead_code)]
+    pub fn into_raw(rc: Self) -> *const T {
+        let ptr = Self::as_ptr(&rc);
+        mem::forget(rc);
+        ptr
+    }
+
+    /// # Safety
+    /// `ptr` has to come from `into_raw`, and every call takes back one `Rc`.
+    #[allow(dead_code)]
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let (_, offset) = Layout::new::<RcData<()>>()
+            .extend(Layout::for_value(&*ptr))
+            .unwrap();
+        Rc {
+            ptr: NonNull::new_unchecked(ptr.byte_sub(offset) as *mut RcData<T>),
+            alloc: Global,
+            _not_send_sync: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Deref for Rc<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data().data
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone> Clone for Rc<T, A> {
+    fn clone(&self) -> Self {
+        increment(&self.data().data_ref_count);
+        Rc {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            _not_send_sync: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for Rc<T, A> {
+    fn drop(&mut self) {
+        let count = &self.data().data_ref_count;
+        count.set(count.get() - 1);
+        if count.get() == 0 {
+            unsafe {
+                ManuallyDrop::drop(&mut (*self.ptr.as_ptr()).data);
+
+                // the weak count all Rcs shared
+                drop(Weak {
+                    ptr: self.ptr,
+                    alloc: &self.alloc,
+                    _not_send_sync: PhantomData,
+                });
+            }
+        }
+    }
+}
+
+pub struct Weak<T: ?Sized, A: Allocator = Global> {
+    ptr: NonNull<RcData<T>>,
+    alloc: A,
+    _not_send_sync: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized, A: Allocator> CoerceUnsized<Weak<U, A>> for Weak<T, A> {}
+
+impl<T> Weak<T> {
+    /// A `Weak` that never upgrades, it doesn't allocate.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Weak {
+            ptr: NonNull::new(ptr::without_provenance_mut(usize::MAX)).unwrap(),
+            alloc: Global,
+            _not_send_sync: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Weak<T, A> {
+    fn is_dangling(&self) -> bool {
+        self.ptr.as_ptr() as *mut () as usize == usize::MAX
+    }
+
+    fn data(&self) -> &RcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    #[allow(dead_code)]
+    pub fn upgrade(&self) -> Option<Rc<T, A>>
+    where
+        A: Clone,
+    {
+        if self.is_dangling() || self.data().data_ref_count.get() == 0 {
+            return None;
+        }
+
+        increment(&self.data().data_ref_count);
+        Some(Rc {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            _not_send_sync: PhantomData,
+        })
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone> Clone for Weak<T, A> {
+    fn clone(&self) -> Self {
+        if !self.is_dangling() {
+            increment(&self.data().alloc_ref_count);
+        }
+        Weak {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            _not_send_sync: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for Weak<T, A> {
+    fn drop(&mut self) {
+        if self.is_dangling() {
+            return;
+        }
+
+        let count = &self.data().alloc_ref_count;
+        count.set(count.get() - 1);
+        if count.get() == 0 {
+            unsafe {
+                drop(Box::from_raw_in(self.ptr.as_ptr(), &self.alloc));
+            }
+        }
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Debug for Weak<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(Weak)")
+    }
+}
+
+impl<A: Allocator> Rc<dyn Any, A> {
+    /// Gets the `Rc` of the concrete type back, or itself if that's not the type.
+    #[allow(dead_code)]
+    pub fn downcast<T: Any>(self) -> Result<Rc<T, A>, Self> {
+        if !(*self).is::<T>() {
+            return Err(self);
+        }
+
+        let rc = ManuallyDrop::new(self);
+        Ok(Rc {
+            ptr: rc.ptr.cast::<RcData<T>>(),
+            alloc: unsafe { ptr::read(&rc.alloc) },
+            _not_send_sync: PhantomData,
+        })
+    }
+}
+
+// moves the value out of the box, like `arc::Arc`'s
+impl<T: ?Sized> From<Box<T>> for Rc<T> {
+    fn from(v: Box<T>) -> Self {
+        // data_ref_count and alloc_ref_count
+        let counts = [Cell::new(1usize), Cell::new(1usize)];
+        let data = move_into_counted(counts, v).as_ptr() as *mut RcData<T>;
+        Rc {
+            ptr: unsafe { NonNull::new_unchecked(data) },
+            alloc: Global,
+            _not_send_sync: PhantomData,
+        }
+    }
+}
+
+impl From<&str> for Rc<str> {
+    fn from(v: &str) -> Self {
+        Box::<str>::from(v).into()
+    }
+}
+
+impl From<String> for Rc<str> {
+    fn from(v: String) -> Self {
+        v.into_boxed_str().into()
+    }
+}
+
+impl<T: Clone> From<&[T]> for Rc<[T]> {
+    fn from(v: &[T]) -> Self {
+        Box::<[T]>::from(v).into()
+    }
+}
+
+impl<T> From<Vec<T>> for Rc<[T]> {
+    fn from(v: Vec<T>) -> Self {
+        v.into_boxed_slice().into()
+    }
+}
+
+impl<T> FromIterator<T> for Rc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<T>>().into()
+    }
+}
+
+impl<T: Default> Default for Rc<T> {
+    fn default() -> Self {
+        Rc::new(T::default())
+    }
+}
+
+impl<T> From<T> for Rc<T> {
+    fn from(v: T) -> Self {
+        Rc::new(v)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> AsRef<T> for Rc<T, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Borrow<T> for Rc<T, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for Rc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, A: Allocator> fmt::Display for Rc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Pointer for Rc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&Self::as_ptr(self), f)
+    }
+}
+
+impl<T: ?Sized + PartialEq, A: Allocator> PartialEq for Rc<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for Rc<T, A> {}
+
+impl<T: ?Sized + PartialOrd, A: Allocator> PartialOrd for Rc<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord, A: Allocator> Ord for Rc<T, A> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash, A: Allocator> Hash for Rc<T, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+#[cfg(test)]
+crate::arc::shared_tests!(Rc);
+
+#[test]
+fn test_rc_downcast() {
+    let any: Rc<dyn Any> = Rc::new(String::from("a"));
+    let any = any.downcast::<i32>().unwrap_err();
+    assert_eq!(*any.downcast::<String>().unwrap(), "a");
+}
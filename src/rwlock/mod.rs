@@ -1,12 +1,30 @@
+use crate::cond_var::RelockGuard;
+use crate::futex;
 use atomic_wait::{wait, wake_all, wake_one};
 use std::{
     cell::UnsafeCell,
-    fmt::Write,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    panic::{RefUnwindSafe, UnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        LockResult, PoisonError, TryLockError, TryLockResult,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-pub struct RwLock<T> {
+mod policy;
+
+#[allow(unused_imports)]
+pub use policy::{PhaseFair, ReaderPreferred, RwPolicy, WriterPreferred};
+
+// the most read locks the state can count, 2 * MAX_READERS + 1 still stays below
+// u32::MAX, which would mean write locked.
+const MAX_READERS: u32 = u32::MAX / 2 - 1;
+
+pub struct RwLock<T, P: RwPolicy = WriterPreferred> {
     // u32:MAX represents Write Locked
     // 0 represents UNLOCKED
     // others represents the number of READER LOCKS
@@ -16,55 +34,290 @@ pub struct RwLock<T> {
     value: UnsafeCell<T>,
     // record the num of writers
     num_writers: AtomicU32,
+    // 0: no upgradable reader, 1: held, 2: held and others are waiting
+    upgradable: AtomicU32,
+    // only used by policies where writers yield:
+    // incremented every time a writer unlocks, readers that waited across it are let in first
+    phase: AtomicU32,
+    // blocked readers, indexed by the parity of the phase they started waiting in
+    readers_waiting: [AtomicU32; 2],
+    /// Set when a thread panics while holding the write lock, the value may be in a broken state.
+    poisoned: AtomicBool,
+    _policy: PhantomData<P>,
 }
 
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+unsafe impl<T, P: RwPolicy> Sync for RwLock<T, P> where T: Send + Sync {}
+
+// a panic can't leave the value broken unnoticed, the lock will be poisoned.
+impl<T, P: RwPolicy> UnwindSafe for RwLock<T, P> {}
+impl<T, P: RwPolicy> RefUnwindSafe for RwLock<T, P> {}
 
 #[allow(dead_code)]
 impl<T> RwLock<T> {
     pub fn new(value: T) -> Self {
+        Self::with_policy(value, WriterPreferred)
+    }
+}
+
+#[allow(dead_code)]
+impl<T, P: RwPolicy> RwLock<T, P> {
+    // the policy is only a marker, e.g. `RwLock::with_policy(0, PhaseFair)`
+    pub fn with_policy(value: T, _policy: P) -> Self {
         Self {
             state: AtomicU32::new(0),
             writer_wake_counter: AtomicU32::new(0),
             value: UnsafeCell::new(value),
             num_writers: AtomicU32::new(0),
+            upgradable: AtomicU32::new(0),
+            phase: AtomicU32::new(0),
+            readers_waiting: [AtomicU32::new(0), AtomicU32::new(0)],
+            poisoned: AtomicBool::new(false),
+            _policy: PhantomData,
         }
     }
 
-    pub fn read(&self) -> ReadGuide<'_, T> {
+    pub fn read(&self) -> LockResult<ReadGuide<'_, T, P>> {
+        self.lock_read(None);
+        self.poison_result(ReadGuide { mutex: self })
+    }
+
+    pub fn try_read(&self) -> TryLockResult<ReadGuide<'_, T, P>> {
+        let mut n = self.state.load(Ordering::Relaxed);
+        while Self::reader_may_enter(n, false) && n / 2 < MAX_READERS {
+            match self
+                .state
+                .compare_exchange(n, n + 2, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok(self.poison_result(ReadGuide { mutex: self })?),
+                Err(e) => n = e,
+            }
+        }
+
+        Err(TryLockError::WouldBlock)
+    }
+
+    pub fn try_read_for(&self, timeout: Duration) -> TryLockResult<ReadGuide<'_, T, P>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_read_until(deadline),
+            // too far in the future to ever time out
+            None => Ok(self.read()?),
+        }
+    }
+
+    pub fn try_read_until(&self, deadline: Instant) -> TryLockResult<ReadGuide<'_, T, P>> {
+        if !self.lock_read(Some(deadline)) {
+            return Err(TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison_result(ReadGuide { mutex: self })?)
+    }
+
+    // `admitted` is whether a writer unlocked since this reader started waiting.
+    fn reader_may_enter(n: u32, admitted: bool) -> bool {
+        // u32::MAX is odd as well
+        n != u32::MAX && (n.is_multiple_of(2) || !P::READERS_YIELD || admitted)
+    }
+
+    // the state for one more reader, a blocking read can't fail, so it panics like std
+    // rather than letting the count run into the write locked state.
+    // a timed read can fail, so it gets None instead.
+    fn add_reader(n: u32, deadline: Option<Instant>) -> Option<u32> {
+        if n / 2 >= MAX_READERS && deadline.is_some() {
+            return None;
+        }
+        assert!(n / 2 < MAX_READERS, "too many active read locks on RwLock");
+        Some(n + 2)
+    }
+
+    // returns false if the deadline passed before the lock could be taken, or if a timed
+    // read found the reader count full. a waiting reader doesn't change the state so
+    // there is nothing to undo.
+    fn lock_read(&self, deadline: Option<Instant>) -> bool {
+        if !P::WRITERS_YIELD {
+            return self.lock_read_contended(0, deadline);
+        }
+
+        let mut n = self.state.load(Ordering::Relaxed);
+        while Self::reader_may_enter(n, false) {
+            let Some(m) = Self::add_reader(n, deadline) else {
+                return false;
+            };
+            match self
+                .state
+                .compare_exchange(n, m, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(e) => n = e,
+            }
+        }
+
+        // register as waiting, so the writer that unlocks next lets us in first
+        let waiting = WaitingReader::new(self);
+        self.lock_read_contended(waiting.phase, deadline)
+    }
+
+    fn lock_read_contended(&self, phase: u32, deadline: Option<Instant>) -> bool {
         let mut n = self.state.load(Ordering::Relaxed);
         loop {
-            if n % 2 == 0 {
+            let admitted = P::WRITERS_YIELD && self.phase.load(Ordering::SeqCst) != phase;
+            if Self::reader_may_enter(n, admitted) {
+                let Some(m) = Self::add_reader(n, deadline) else {
+                    return false;
+                };
                 match self
                     .state
-                    .compare_exchange(n, n + 2, Ordering::Acquire, Ordering::Relaxed)
+                    .compare_exchange(n, m, Ordering::Acquire, Ordering::Relaxed)
                 {
-                    Ok(_) => return ReadGuide { mutex: self },
+                    Ok(_) => return true,
                     Err(p) => {
                         n = p;
+                        continue;
                     }
                 }
             }
 
             // block more readers if there are writers waiting
-            if n % 2 == 1 {
-                wait(&self.state, u32::MAX);
-                n = self.state.load(Ordering::Relaxed);
+            if !futex::wait_until(&self.state, n, deadline) {
+                return false;
+            }
+            n = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    /// Takes a read lock that can later be upgraded to a write lock without
+    /// releasing it. It coexists with plain readers, but only one upgradable
+    /// reader can exist at a time.
+    pub fn upgradable_read(&self) -> LockResult<UpgradableReadGuide<'_, T, P>> {
+        if self
+            .upgradable
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.upgradable.swap(2, Ordering::Acquire) != 0 {
+                wait(&self.upgradable, 2);
             }
         }
+
+        // give the token back if the read lock panics, or no one could upgrade again
+        let token = UpgradableToken { rwlock: self };
+        self.lock_read(None);
+        mem::forget(token);
+        self.poison_result(UpgradableReadGuide { mutex: self })
+    }
+
+    fn unlock_read(&self) {
+        let n = self.state.fetch_sub(2, Ordering::Release);
+        if n == 3 {
+            self.writer_wake_counter.fetch_add(1, Ordering::Release);
+            wake_one(&self.writer_wake_counter);
+        } else if n == 5 && self.upgradable.load(Ordering::Relaxed) != 0 {
+            // only one reader is left, it may be the upgradable reader waiting to upgrade.
+            // other writers may be waiting too, so wake them all up.
+            self.writer_wake_counter.fetch_add(1, Ordering::Release);
+            wake_all(&self.writer_wake_counter);
+        }
+    }
+
+    fn unlock_upgradable(&self) {
+        if self.upgradable.swap(0, Ordering::Release) == 2 {
+            wake_one(&self.upgradable);
+        }
+    }
+
+    pub fn write(&self) -> LockResult<WriteGuide<'_, T, P>> {
+        self.lock_write(None);
+        self.poison_result(WriteGuide::new(self))
+    }
+
+    pub fn try_write(&self) -> TryLockResult<WriteGuide<'_, T, P>> {
+        if self.readers_admitted() {
+            return Err(TryLockError::WouldBlock);
+        }
+
+        if self
+            .state
+            .compare_exchange(0, u32::MAX, Ordering::Acquire, Ordering::Relaxed)
+            .or_else(|_| {
+                // only writers are waiting, we can take it before them like `write` does
+                self.state
+                    .compare_exchange(1, u32::MAX, Ordering::Acquire, Ordering::Relaxed)
+            })
+            .is_err()
+        {
+            return Err(TryLockError::WouldBlock);
+        }
+
+        // nobody can release the lock before this, so it's fine to count ourselves late
+        self.num_writers.fetch_add(1, Ordering::Relaxed);
+        Ok(self.poison_result(WriteGuide::new(self))?)
+    }
+
+    pub fn try_write_for(&self, timeout: Duration) -> TryLockResult<WriteGuide<'_, T, P>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_write_until(deadline),
+            // too far in the future to ever time out
+            None => Ok(self.write()?),
+        }
+    }
+
+    pub fn try_write_until(&self, deadline: Instant) -> TryLockResult<WriteGuide<'_, T, P>> {
+        if !self.lock_write(Some(deadline)) {
+            return Err(TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison_result(WriteGuide::new(self))?)
+    }
+
+    fn poison_result<G>(&self, guard: G) -> LockResult<G> {
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let value = self.value.into_inner();
+
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    // &mut self guarantees no guard is alive, so there is no need to touch the state.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        let value = self.value.get_mut();
+
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
     }
 
-    pub fn write(&self) -> WriteGuide<'_, T> {
+    fn lock_write(&self, deadline: Option<Instant>) -> bool {
         let mut n = self.state.load(Ordering::Relaxed);
         self.num_writers.fetch_add(1, Ordering::Relaxed);
 
         loop {
-            if n <= 1 {
+            if n <= 1 && !self.readers_admitted() {
                 match self
                     .state
                     .compare_exchange(n, u32::MAX, Ordering::Acquire, Ordering::Relaxed)
                 {
-                    Ok(_) => return WriteGuide { mutex: self },
+                    Ok(_) => return true,
                     Err(e) => {
                         n = e;
                         continue;
@@ -72,7 +325,7 @@ impl<T> RwLock<T> {
                 }
             }
 
-            if n % 2 == 0 {
+            if n.is_multiple_of(2) {
                 match self
                     .state
                     .compare_exchange(n, n + 1, Ordering::Acquire, Ordering::Relaxed)
@@ -86,36 +339,194 @@ impl<T> RwLock<T> {
             }
 
             // before the writer get into sleep, we must confirm it could not get the lock and make the state odd.
-            let w = self.writer_wake_counter.load(Ordering::Relaxed);
+            let w = self.writer_wake_counter.load(Ordering::Acquire);
             n = self.state.load(Ordering::Relaxed);
-            if n >= 2 {
-                wait(&self.writer_wake_counter, w);
+            if n >= 2 || self.readers_admitted() {
+                if !futex::wait_until(&self.writer_wake_counter, w, deadline) {
+                    self.abandon_write();
+                    return false;
+                }
                 n = self.state.load(Ordering::Relaxed);
             }
         }
     }
+
+    // whether readers that waited for the last writer still have to get in.
+    fn readers_admitted(&self) -> bool {
+        if !P::WRITERS_YIELD {
+            return false;
+        }
+
+        let last_phase = self.phase.load(Ordering::SeqCst).wrapping_sub(1);
+        self.readers_waiting[(last_phase % 2) as usize].load(Ordering::SeqCst) > 0
+    }
+
+    // `downgrade` keeps one read lock for the writer itself.
+    fn unlock_write(&self, downgrade: bool) {
+        let read = if downgrade { 2 } else { 0 };
+        if P::WRITERS_YIELD {
+            // the readers waiting so far go before the next writer
+            self.phase.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let pre_num_writers = self.num_writers.fetch_sub(1, Ordering::Release);
+        if pre_num_writers > 1 {
+            self.state.store(read + 1, Ordering::Release);
+            if P::WRITERS_YIELD {
+                wake_all(&self.state);
+            }
+            // after a downgrade, our own read lock wakes up the next writer
+            if !downgrade {
+                self.writer_wake_counter.fetch_add(1, Ordering::Release);
+                wake_one(&self.writer_wake_counter);
+            }
+        } else {
+            // it's alright if there is writer get into in this moment, cause it's negligible performance lost.
+            self.state.store(read, Ordering::Release);
+            wake_all(&self.state);
+        }
+    }
+
+    // undo what a timed out writer did to the lock.
+    fn abandon_write(&self) {
+        if self.num_writers.fetch_sub(1, Ordering::Relaxed) > 1 {
+            // the others still need the readers blocked
+            return;
+        }
+
+        // we may have made the state odd, stop blocking new readers
+        let mut n = self.state.load(Ordering::Relaxed);
+        while n != u32::MAX && !n.is_multiple_of(2) {
+            match self
+                .state
+                .compare_exchange(n, n - 1, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    wake_all(&self.state);
+                    // a writer that just came in may already rely on the bit we cleared,
+                    // let it check the state again.
+                    self.writer_wake_counter.fetch_add(1, Ordering::Release);
+                    wake_one(&self.writer_wake_counter);
+                    return;
+                }
+                Err(e) => n = e,
+            }
+        }
+    }
+}
+
+pub struct WriteGuide<'a, T, P: RwPolicy = WriterPreferred> {
+    mutex: &'a RwLock<T, P>,
+    // whether the thread was already panicking when the lock was taken,
+    // so that unwinding through an unrelated panic doesn't poison the lock.
+    panicking: bool,
+}
+
+pub struct ReadGuide<'a, T, P: RwPolicy = WriterPreferred> {
+    mutex: &'a RwLock<T, P>,
+}
+
+pub struct UpgradableReadGuide<'a, T, P: RwPolicy = WriterPreferred> {
+    mutex: &'a RwLock<T, P>,
 }
 
-pub struct WriteGuide<'a, T> {
-    mutex: &'a RwLock<T>,
+impl<T, P: RwPolicy> Drop for ReadGuide<'_, T, P> {
+    fn drop(&mut self) {
+        self.mutex.unlock_read();
+    }
 }
 
-pub struct ReadGuide<'a, T> {
-    mutex: &'a RwLock<T>,
+#[allow(dead_code)]
+impl<'a, T, P: RwPolicy> UpgradableReadGuide<'a, T, P> {
+    /// Turns the upgradable read lock into a write lock, waiting for the
+    /// other readers to leave. New readers are blocked meanwhile.
+    pub fn upgrade(guard: Self) -> WriteGuide<'a, T, P> {
+        let rwlock = ManuallyDrop::new(guard).mutex;
+        rwlock.num_writers.fetch_add(1, Ordering::Relaxed);
+
+        let mut n = rwlock.state.load(Ordering::Relaxed);
+        loop {
+            // our own read lock is the only one left
+            if n <= 3 {
+                match rwlock.state.compare_exchange(
+                    n,
+                    u32::MAX,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => {
+                        n = e;
+                        continue;
+                    }
+                }
+            }
+
+            if n.is_multiple_of(2) {
+                if let Err(e) =
+                    rwlock
+                        .state
+                        .compare_exchange(n, n + 1, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    n = e;
+                    continue;
+                }
+            }
+
+            let w = rwlock.writer_wake_counter.load(Ordering::Acquire);
+            n = rwlock.state.load(Ordering::Relaxed);
+            if n > 3 {
+                wait(&rwlock.writer_wake_counter, w);
+                n = rwlock.state.load(Ordering::Relaxed);
+            }
+        }
+
+        // the write lock excludes everyone, let the next upgradable reader queue up
+        rwlock.unlock_upgradable();
+        WriteGuide::new(rwlock)
+    }
 }
 
-impl<T> Drop for ReadGuide<'_, T> {
+impl<T, P: RwPolicy> Drop for UpgradableReadGuide<'_, T, P> {
     fn drop(&mut self) {
-        if self.mutex.state.fetch_sub(2, Ordering::Relaxed) == 3 {
-            self.mutex
-                .writer_wake_counter
-                .fetch_add(1, Ordering::Release);
-            wake_one(&self.mutex.writer_wake_counter);
+        self.mutex.unlock_read();
+        self.mutex.unlock_upgradable();
+    }
+}
+
+impl<'a, T, P: RwPolicy> Deref for UpgradableReadGuide<'a, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+#[allow(dead_code)]
+impl<'a, T, P: RwPolicy> WriteGuide<'a, T, P> {
+    fn new(rwlock: &'a RwLock<T, P>) -> Self {
+        Self {
+            mutex: rwlock,
+            panicking: thread::panicking(),
+        }
+    }
+
+    /// Turns the write lock into a read lock without letting another writer in between.
+    pub fn downgrade(guard: Self) -> ReadGuide<'a, T, P> {
+        let guard = ManuallyDrop::new(guard);
+        guard.poison();
+        guard.mutex.unlock_write(true);
+        ReadGuide { mutex: guard.mutex }
+    }
+
+    fn poison(&self) {
+        if !self.panicking && thread::panicking() {
+            self.mutex.poisoned.store(true, Ordering::Relaxed);
         }
     }
 }
 
-impl<'a, T> Deref for ReadGuide<'a, T> {
+impl<'a, T, P: RwPolicy> Deref for ReadGuide<'a, T, P> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -123,7 +534,7 @@ impl<'a, T> Deref for ReadGuide<'a, T> {
     }
 }
 
-impl<'a, T> Deref for WriteGuide<'a, T> {
+impl<'a, T, P: RwPolicy> Deref for WriteGuide<'a, T, P> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -131,40 +542,94 @@ impl<'a, T> Deref for WriteGuide<'a, T> {
     }
 }
 
-impl<'a, T> DerefMut for WriteGuide<'a, T> {
+impl<'a, T, P: RwPolicy> DerefMut for WriteGuide<'a, T, P> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.mutex.value.get() }
     }
 }
 
-impl<'a, T> Drop for WriteGuide<'a, T> {
+impl<'a, T, P: RwPolicy> Drop for WriteGuide<'a, T, P> {
     fn drop(&mut self) {
-        let pre_num_writers = self.mutex.num_writers.fetch_sub(1, Ordering::Release);
-        if pre_num_writers > 1 {
-            self.mutex.state.store(1, Ordering::Release);
-            self.mutex
-                .writer_wake_counter
-                .fetch_add(1, Ordering::Release);
-            wake_one(&self.mutex.writer_wake_counter);
-        } else {
-            // it's alright if there is writer get into in this moment, cause it's negligible performance lost.
-            self.mutex.state.store(0, Ordering::Release);
-            wake_all(&self.mutex.state);
+        self.poison();
+        self.mutex.unlock_write(false);
+    }
+}
+
+impl<'a, T, P: RwPolicy> RelockGuard<'a> for WriteGuide<'a, T, P> {
+    type Lock = RwLock<T, P>;
+
+    fn lock(&self) -> &'a RwLock<T, P> {
+        self.mutex
+    }
+
+    fn relock(rwlock: &'a RwLock<T, P>, _slept: bool) -> LockResult<Self> {
+        rwlock.write()
+    }
+}
+
+impl<'a, T, P: RwPolicy> RelockGuard<'a> for ReadGuide<'a, T, P> {
+    type Lock = RwLock<T, P>;
+
+    fn lock(&self) -> &'a RwLock<T, P> {
+        self.mutex
+    }
+
+    fn relock(rwlock: &'a RwLock<T, P>, _slept: bool) -> LockResult<Self> {
+        rwlock.read()
+    }
+}
+
+// counts a blocked reader in the phase it started waiting in, for as long as it
+// waits. Dropping it also uncounts a reader that panicked on too many read locks.
+struct WaitingReader<'a, T, P: RwPolicy> {
+    rwlock: &'a RwLock<T, P>,
+    phase: u32,
+}
+
+impl<'a, T, P: RwPolicy> WaitingReader<'a, T, P> {
+    fn new(rwlock: &'a RwLock<T, P>) -> Self {
+        // register as waiting, so the writer that unlocks next lets us in first
+        let phase = rwlock.phase.load(Ordering::SeqCst);
+        rwlock.readers_waiting[(phase % 2) as usize].fetch_add(1, Ordering::SeqCst);
+        Self { rwlock, phase }
+    }
+}
+
+impl<T, P: RwPolicy> Drop for WaitingReader<'_, T, P> {
+    fn drop(&mut self) {
+        let rwlock = self.rwlock;
+        let waiting = &rwlock.readers_waiting[(self.phase % 2) as usize];
+        if waiting.fetch_sub(1, Ordering::SeqCst) == 1
+            && rwlock.phase.load(Ordering::SeqCst) != self.phase
+        {
+            // writers may be waiting for the readers we were counted with
+            rwlock.writer_wake_counter.fetch_add(1, Ordering::Release);
+            wake_one(&rwlock.writer_wake_counter);
         }
     }
 }
 
+struct UpgradableToken<'a, T, P: RwPolicy> {
+    rwlock: &'a RwLock<T, P>,
+}
+
+impl<T, P: RwPolicy> Drop for UpgradableToken<'_, T, P> {
+    fn drop(&mut self) {
+        self.rwlock.unlock_upgradable();
+    }
+}
+
 #[test]
 fn test_rw_lock() {
     let rwlock = RwLock::new(0);
     std::thread::scope(|s| {
         for _ in 0..30 {
             s.spawn(|| {
-                let rlock = rwlock.read();
+                let rlock = rwlock.read().unwrap();
                 println!("read lock: {}", *rlock);
             });
             s.spawn(|| {
-                let mut wlock = rwlock.write();
+                let mut wlock = rwlock.write().unwrap();
                 *wlock += 1;
                 println!("write lock: {}", *wlock);
             });
@@ -174,7 +639,447 @@ fn test_rw_lock() {
     println!("over");
 }
 
+#[test]
+fn test_upgradable_read() {
+    use std::{thread, time::Duration};
+
+    let rwlock = RwLock::new(0);
+
+    let upgradable = rwlock.upgradable_read().unwrap();
+    // plain readers can still come in
+    let r = rwlock.read().unwrap();
+    assert_eq!(*r, *upgradable);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            // blocks until the first upgradable reader is gone
+            let u = rwlock.upgradable_read().unwrap();
+            assert_eq!(*u, 1);
+        });
+
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            // waits for the upgrade, which itself waits for this reader
+            drop(r);
+        });
+
+        let mut w = UpgradableReadGuide::upgrade(upgradable);
+        *w += 1;
+    });
+
+    assert_eq!(*rwlock.read().unwrap(), 1);
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_upgrade_contended() {
+    let rwlock = RwLock::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let u = rwlock.upgradable_read().unwrap();
+                    if *u % 2 == 0 {
+                        *UpgradableReadGuide::upgrade(u) += 1;
+                    } else {
+                        drop(u);
+                        *rwlock.write().unwrap() += 1;
+                    }
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let r = rwlock.read().unwrap();
+                    assert!(*r <= 4000);
+                }
+            });
+        }
+    });
+
+    assert_eq!(*rwlock.read().unwrap(), 4000);
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_downgrade() {
+    use std::{thread, time::Duration};
+
+    let rwlock = RwLock::new(0);
+    let mut w = rwlock.write().unwrap();
+    *w += 1;
+
+    thread::scope(|s| {
+        let writer = s.spawn(|| {
+            *rwlock.write().unwrap() += 1;
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // no other writer can get in between
+        let r = WriteGuide::downgrade(w);
+        assert_eq!(*r, 1);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        assert_eq!(*r, 1);
+    });
+
+    assert_eq!(*rwlock.read().unwrap(), 2);
+
+    // without waiting writers, blocked readers can join the downgraded lock
+    let w = rwlock.write().unwrap();
+    thread::scope(|s| {
+        let reader = s.spawn(|| *rwlock.read().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        let r = WriteGuide::downgrade(w);
+        assert_eq!(reader.join().unwrap(), 2);
+        drop(r);
+    });
+
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_try_lock() {
+    let rwlock = RwLock::new(0);
+
+    let r = rwlock.try_read().unwrap();
+    assert!(rwlock.try_read().is_ok());
+    assert!(rwlock.try_write().is_err());
+    drop(r);
+
+    let mut w = rwlock.try_write().unwrap();
+    *w += 1;
+    assert!(rwlock.try_read().is_err());
+    assert!(rwlock.try_write().is_err());
+    drop(w);
+
+    assert_eq!(*rwlock.try_read().unwrap(), 1);
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+    assert_eq!(rwlock.num_writers.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_try_write_timeout() {
+    use std::{thread, time::Duration};
+
+    let rwlock = RwLock::new(0);
+    let r = rwlock.read().unwrap();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let start = Instant::now();
+            assert!(rwlock.try_write_for(Duration::from_millis(100)).is_err());
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        });
+
+        // wait for the writer to block new readers
+        while rwlock.state.load(Ordering::Relaxed).is_multiple_of(2) {
+            thread::yield_now();
+        }
+        assert!(rwlock.try_read().is_err());
+        assert!(rwlock.try_read_for(Duration::from_millis(10)).is_err());
+
+        // once the writer gave up, the blocked reader gets in while the first is still held
+        let reader = s.spawn(|| *rwlock.read().unwrap());
+        assert_eq!(reader.join().unwrap(), 0);
+    });
+
+    drop(r);
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+    assert_eq!(rwlock.num_writers.load(Ordering::Relaxed), 0);
+    *rwlock.try_write().unwrap() += 1;
+}
+
+#[test]
+fn test_timed_contended() {
+    let rwlock = RwLock::new(0);
+    let writes = std::sync::atomic::AtomicU32::new(0);
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..2000 {
+                    if let Ok(mut w) = rwlock.try_write_for(Duration::from_micros(20)) {
+                        *w += 1;
+                        writes.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..2000 {
+                    *rwlock.write().unwrap() += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..2000 {
+                    if let Ok(r) = rwlock.try_read_for(Duration::from_micros(20)) {
+                        assert!(*r <= 16_000);
+                    }
+                    drop(rwlock.read().unwrap());
+                }
+            });
+        }
+    });
+
+    assert_eq!(
+        *rwlock.read().unwrap(),
+        8000 + writes.load(Ordering::Relaxed)
+    );
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+    assert_eq!(rwlock.num_writers.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_poison() {
+    use std::thread;
+
+    let mut rwlock = RwLock::new(0);
+
+    // a panicking reader can't break the value
+    let r = thread::scope(|s| {
+        s.spawn(|| {
+            let _r = rwlock.read().unwrap();
+            panic!("panic while reading");
+        })
+        .join()
+    });
+    assert!(r.is_err());
+    assert!(!rwlock.is_poisoned());
+
+    let r = thread::scope(|s| {
+        s.spawn(|| {
+            let mut w = rwlock.write().unwrap();
+            *w += 1;
+            panic!("panic while writing");
+        })
+        .join()
+    });
+    assert!(r.is_err());
+    assert!(rwlock.is_poisoned());
+
+    assert!(rwlock.read().is_err());
+    assert_eq!(*rwlock.read().unwrap_or_else(PoisonError::into_inner), 1);
+    assert!(matches!(rwlock.try_write(), Err(TryLockError::Poisoned(_))));
+    *rwlock.write().unwrap_or_else(PoisonError::into_inner) += 1;
+    assert_eq!(*rwlock.get_mut().unwrap_err().into_inner(), 2);
+
+    rwlock.clear_poison();
+    assert_eq!(*rwlock.read().unwrap(), 2);
+    assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+    assert_eq!(rwlock.into_inner().unwrap(), 2);
+}
+
+#[test]
+fn test_reader_overflow() {
+    use std::{mem, panic, thread};
+
+    // start close to the limit, counting all 2^31 read locks would take too long
+    const LEFT: u32 = 10_000;
+
+    fn forget_guards<P: RwPolicy>(policy: P, writer_waiting: bool) {
+        let rwlock = RwLock::with_policy((), policy);
+        let full = 2 * MAX_READERS + writer_waiting as u32;
+        rwlock.state.store(full - 2 * LEFT, Ordering::Relaxed);
+        let taken = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    // leak read locks until the lock refuses to count more
+                    while panic::catch_unwind(|| mem::forget(rwlock.read())).is_ok() {
+                        taken.fetch_add(1, Ordering::Relaxed);
+                        assert_ne!(rwlock.state.load(Ordering::Relaxed), u32::MAX);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(taken.load(Ordering::Relaxed), LEFT);
+        assert_eq!(rwlock.state.load(Ordering::Relaxed), full);
+        assert!(matches!(rwlock.try_read(), Err(TryLockError::WouldBlock)));
+        assert!(matches!(rwlock.try_write(), Err(TryLockError::WouldBlock)));
+        // a timed read gives up instead of panicking
+        assert!(matches!(
+            rwlock.try_read_for(Duration::from_millis(10)),
+            Err(TryLockError::WouldBlock)
+        ));
+        // an upgradable read panics too, but gives its token back
+        assert!(panic::catch_unwind(|| drop(rwlock.upgradable_read())).is_err());
+        assert_eq!(rwlock.upgradable.load(Ordering::Relaxed), 0);
+        assert_eq!(rwlock.state.load(Ordering::Relaxed), full);
+        // the readers that panicked while waiting aren't counted anymore
+        assert_eq!(rwlock.readers_waiting[0].load(Ordering::Relaxed), 0);
+        assert_eq!(rwlock.readers_waiting[1].load(Ordering::Relaxed), 0);
+        assert!(!rwlock.is_poisoned());
+
+        // once a reader leaves, there is room for one more
+        rwlock.unlock_read();
+        mem::forget(rwlock.try_read().unwrap());
+        assert_eq!(rwlock.state.load(Ordering::Relaxed), full);
+    }
+
+    forget_guards(WriterPreferred, false);
+    forget_guards(PhaseFair, false);
+    forget_guards(ReaderPreferred, false);
+    // readers don't wait for writers here, so they can count up from an odd state
+    forget_guards(ReaderPreferred, true);
+}
+
 #[test]
 fn test_u32_odd_even() {
     println!("{}", u32::MAX % 2);
 }
+
+// the contenders give up after this many acquisitions while someone waits, so a starved
+// thread still gets the lock and the test fails instead of hanging
+#[allow(dead_code)]
+const CONTENDED_ROUNDS: usize = 1_000;
+
+// hammers the lock with reads or writes from a few threads until a single acquisition of
+// the other kind gets through, and returns how many times the contenders took the lock
+// while it was waiting.
+#[allow(dead_code)]
+fn acquisitions_while_waiting<P: RwPolicy>(policy: P, hammer_with_writes: bool) -> usize {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize},
+        thread,
+    };
+
+    let rwlock = RwLock::with_policy(0, policy);
+    let waiting = AtomicBool::new(false);
+    let acquired = AtomicBool::new(false);
+    let rounds = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                while !acquired.load(Ordering::Relaxed)
+                    && rounds.load(Ordering::Relaxed) < CONTENDED_ROUNDS
+                {
+                    let hold = || {
+                        if waiting.load(Ordering::Relaxed) {
+                            rounds.fetch_add(1, Ordering::Relaxed);
+                        }
+                        thread::sleep(Duration::from_micros(100));
+                    };
+                    if hammer_with_writes {
+                        let _w = rwlock.write().unwrap();
+                        hold();
+                    } else {
+                        let _r = rwlock.read().unwrap();
+                        hold();
+                    }
+                }
+            });
+        }
+
+        thread::sleep(Duration::from_millis(20));
+        waiting.store(true, Ordering::Relaxed);
+        if hammer_with_writes {
+            drop(rwlock.read().unwrap());
+        } else {
+            drop(rwlock.write().unwrap());
+        }
+        acquired.store(true, Ordering::Relaxed);
+    });
+
+    rounds.into_inner()
+}
+
+#[test]
+fn test_writer_preferred() {
+    let rwlock = RwLock::with_policy(0, WriterPreferred);
+    let r = rwlock.read().unwrap();
+    std::thread::scope(|s| {
+        s.spawn(|| *rwlock.write().unwrap() += 1);
+        while rwlock.state.load(Ordering::Relaxed) != 3 {
+            std::thread::yield_now();
+        }
+        // a waiting writer blocks new readers
+        assert!(rwlock.try_read().is_err());
+        drop(r);
+    });
+
+    // so a stream of readers can't starve a writer
+    assert!(acquisitions_while_waiting(WriterPreferred, false) < CONTENDED_ROUNDS);
+}
+
+#[test]
+fn test_reader_preferred() {
+    let rwlock = RwLock::with_policy(0, ReaderPreferred);
+    let r = rwlock.read().unwrap();
+    std::thread::scope(|s| {
+        s.spawn(|| *rwlock.write().unwrap() += 1);
+        while rwlock.state.load(Ordering::Relaxed) != 3 {
+            std::thread::yield_now();
+        }
+        // a waiting writer doesn't block new readers
+        assert_eq!(*rwlock.try_read().unwrap(), 0);
+        drop(r);
+    });
+    assert_eq!(*rwlock.read().unwrap(), 1);
+
+    // and a stream of writers can't starve a reader
+    assert!(acquisitions_while_waiting(ReaderPreferred, true) < CONTENDED_ROUNDS);
+}
+
+#[test]
+fn test_phase_fair() {
+    let rwlock = RwLock::with_policy(0, PhaseFair);
+    let r = rwlock.read().unwrap();
+    std::thread::scope(|s| {
+        s.spawn(|| *rwlock.write().unwrap() += 1);
+        while rwlock.state.load(Ordering::Relaxed) != 3 {
+            std::thread::yield_now();
+        }
+        assert!(rwlock.try_read().is_err());
+        drop(r);
+    });
+    assert_eq!(*rwlock.read().unwrap(), 1);
+
+    // neither side can starve the other
+    assert!(acquisitions_while_waiting(PhaseFair, false) < CONTENDED_ROUNDS);
+    assert!(acquisitions_while_waiting(PhaseFair, true) < CONTENDED_ROUNDS);
+}
+
+#[test]
+fn test_policies_contended() {
+    fn count<P: RwPolicy>(policy: P) {
+        let rwlock = RwLock::with_policy(0, policy);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        *rwlock.write().unwrap() += 1;
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        if let Ok(mut w) = rwlock.try_write_for(Duration::from_micros(10)) {
+                            *w += 1;
+                            *w -= 1;
+                        }
+                        let u = rwlock.upgradable_read().unwrap();
+                        if *u % 2 == 0 {
+                            let w = UpgradableReadGuide::upgrade(u);
+                            drop(WriteGuide::downgrade(w));
+                        }
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        drop(rwlock.read().unwrap());
+                        drop(rwlock.try_read_for(Duration::from_micros(10)));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(*rwlock.read().unwrap(), 8000);
+        assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+        assert_eq!(rwlock.num_writers.load(Ordering::Relaxed), 0);
+    }
+
+    count(WriterPreferred);
+    count(ReaderPreferred);
+    count(PhaseFair);
+}
@@ -0,0 +1,37 @@
+/// Decides who goes first when readers and writers compete for the `RwLock`.
+pub trait RwPolicy {
+    /// New readers wait while a writer is waiting, so readers can't starve writers.
+    const READERS_YIELD: bool;
+    /// Readers that had to wait for a writer get in before the next writer,
+    /// so writers can't starve readers.
+    const WRITERS_YIELD: bool;
+}
+
+/// A waiting writer blocks new readers, but writers can starve readers.
+pub struct WriterPreferred;
+
+/// Readers never wait for writers that haven't got the lock yet, but readers
+/// can starve writers.
+#[allow(dead_code)]
+pub struct ReaderPreferred;
+
+/// Read and write phases alternate: a waiting writer blocks new readers, and
+/// when a writer unlocks, all readers that waited for it get in before the
+/// next writer. Neither side can starve the other.
+#[allow(dead_code)]
+pub struct PhaseFair;
+
+impl RwPolicy for WriterPreferred {
+    const READERS_YIELD: bool = true;
+    const WRITERS_YIELD: bool = false;
+}
+
+impl RwPolicy for ReaderPreferred {
+    const READERS_YIELD: bool = false;
+    const WRITERS_YIELD: bool = true;
+}
+
+impl RwPolicy for PhaseFair {
+    const READERS_YIELD: bool = true;
+    const WRITERS_YIELD: bool = true;
+}
@@ -1,8 +1,12 @@
+use crate::cond_var::RelockGuard;
 use std::{
     cell::UnsafeCell,
     hint::spin_loop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LockResult,
+    },
     thread,
 };
 
@@ -63,6 +67,19 @@ impl<'a, T> Drop for Guard<'a, T> {
     }
 }
 
+// a spin lock can't be poisoned, relocking always succeeds
+impl<'a, T> RelockGuard<'a> for Guard<'a, T> {
+    type Lock = SpinLock<T>;
+
+    fn lock(&self) -> &'a SpinLock<T> {
+        self.lock
+    }
+
+    fn relock(lock: &'a SpinLock<T>, _slept: bool) -> LockResult<Self> {
+        Ok(lock.lock())
+    }
+}
+
 #[allow(dead_code)]
 pub fn spin_lock_usage() {
     let x = SpinLock::new(Vec::new());